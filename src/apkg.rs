@@ -0,0 +1,399 @@
+//! Writing cards into an Anki package (.apkg) that can be opened directly by Anki.
+//! A package is a zip archive holding an SQLite collection (`collection.anki2`) and a
+//! `media` file mapping bundled media to their original names.
+use crate::card::{Card, NoteType};
+use crate::media::Media;
+
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use std::{collections::BTreeSet, env, fs, io::Write, path::Path, process, time::{SystemTime, UNIX_EPOCH}};
+
+// The id Anki uses for the deck configuration every new deck points to
+const DEFAULT_CONF_ID: i64 = 1;
+// Fixed so repeated imports reuse the same note types instead of creating copies
+const BASIC_MODEL_ID: i64 = 1_559_383_000_001;
+const CLOZE_MODEL_ID: i64 = 1_559_383_000_002;
+const REVERSED_MODEL_ID: i64 = 1_559_383_000_003;
+
+const SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null, scm integer not null,
+    ver integer not null, dty integer not null, usn integer not null, ls integer not null,
+    conf text not null, models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null, mod integer not null,
+    usn integer not null, tags text not null, flds text not null, sfld integer not null,
+    csum integer not null, flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null, ord integer not null,
+    mod integer not null, usn integer not null, type integer not null, queue integer not null,
+    due integer not null, ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null, odid integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null, ease integer not null,
+    ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null,
+    type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+";
+
+const CSS: &str = ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n";
+const LATEX_PRE: &str = "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n";
+const LATEX_POST: &str = "\\end{document}";
+const CLOZE_CSS: &str = ".cloze {\n font-weight: bold;\n color: blue;\n}\n";
+
+/// Write `cards` into a new Anki package at `path`, bundling the `media` files they refer to.
+/// Cards are placed in their own deck if they have one and in the deck `deck_name` otherwise.
+/// The id of each card is used as the GUID of its note
+pub fn write(cards: &[Card], media: &[Media], deck_name: &str, path: &Path) -> Result<(), failure::Error> {
+    let collection_path = env::temp_dir().join(format!("md-to-anki-{}.anki2", process::id()));
+    if collection_path.exists() {
+        fs::remove_file(&collection_path)?;
+    }
+    let result = write_collection(cards, deck_name, &collection_path)
+        .and_then(|_| write_package(&collection_path, media, path));
+    let _ = fs::remove_file(&collection_path);
+    result
+}
+
+// Zip the collection database up together with the media files. The files are stored as `0`,
+// `1`, ... and the `media` map gives the name each one has in the media folder
+fn write_package(collection_path: &Path, media: &[Media], path: &Path) -> Result<(), failure::Error> {
+    let file = fs::File::create(path)?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = zip::write::SimpleFileOptions::default();
+    zip.start_file("collection.anki2", options)?;
+    zip.write_all(&fs::read(collection_path)?)?;
+    let mut names = serde_json::Map::new();
+    for (i, file) in media.iter().enumerate() {
+        let data = fs::read(&file.path)
+            .map_err(|e| failure::format_err!("couldn't read `{}`: {}", file.path.display(), e))?;
+        zip.start_file(i.to_string(), options)?;
+        zip.write_all(&data)?;
+        names.insert(i.to_string(), json!(file.name));
+    }
+    zip.start_file("media", options)?;
+    zip.write_all(Value::Object(names).to_string().as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+fn write_collection(cards: &[Card], deck_name: &str, path: &Path) -> Result<(), failure::Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let now_secs = now.as_secs() as i64;
+    let now_millis = now.as_millis() as i64;
+    let deck_id = deck_id(deck_name);
+
+    // Every deck used, along with the decks they are nested in
+    let mut deck_names = BTreeSet::new();
+    deck_names.insert(deck_name.to_string());
+    for name in cards.iter().filter_map(|c| c.deck.as_ref()) {
+        let parts: Vec<&str> = name.split("::").collect();
+        for i in 1..=parts.len() {
+            deck_names.insert(parts[..i].join("::"));
+        }
+    }
+    let mut decks = json!({ "1": deck(1, "Default", now_secs) });
+    for name in &deck_names {
+        decks[self::deck_id(name).to_string()] = deck(self::deck_id(name), name, now_secs);
+    }
+
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    let tx = conn.transaction()?;
+
+    tx.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+        params![
+            now_secs,
+            now_millis,
+            collection_conf(deck_id).to_string(),
+            json!({
+                BASIC_MODEL_ID.to_string(): basic_model(deck_id, now_secs),
+                CLOZE_MODEL_ID.to_string(): cloze_model(deck_id, now_secs),
+                REVERSED_MODEL_ID.to_string(): reversed_model(deck_id, now_secs),
+            }).to_string(),
+            decks.to_string(),
+            json!({ DEFAULT_CONF_ID.to_string(): deck_conf() }).to_string(),
+        ],
+    )?;
+
+    let mut card_id = now_millis;
+    for (i, card) in cards.iter().enumerate() {
+        // Note and card ids only need to be unique, so base them on the current time
+        let id = now_millis + i as i64;
+        let fields = [card.front.as_str(), card.back.as_str()];
+        let card_deck_id = card.deck.as_deref().map_or(deck_id, self::deck_id);
+        let (model_id, ords) = match card.note_type {
+            NoteType::Basic => (BASIC_MODEL_ID, vec![0]),
+            NoteType::BasicAndReversed => (REVERSED_MODEL_ID, vec![0, 1]),
+            NoteType::Cloze => {
+                (CLOZE_MODEL_ID, card.cloze_numbers().iter().map(|n| n - 1).collect())
+            },
+        };
+        tx.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')",
+            params![
+                id,
+                card.id.as_deref().unwrap_or_default(),
+                model_id,
+                now_secs,
+                note_tags(&card.tags),
+                fields.join("\x1f"),
+                card.front,
+                checksum(&card.front),
+            ],
+        )?;
+        // A queue of -1 is how Anki marks a card as suspended
+        let queue = if card.suspended { -1 } else { 0 };
+        for ord in ords {
+            tx.execute(
+                "INSERT INTO cards VALUES (?1, ?2, ?3, ?4, ?5, -1, 0, ?6, ?7, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+                params![card_id, id, card_deck_id, ord as i64, now_secs, queue, i as i64],
+            )?;
+            card_id += 1;
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+// Anki stores a note's tags as a single space separated string padded with spaces
+fn note_tags(tags: &[String]) -> String {
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!(" {} ", tags.join(" "))
+    }
+}
+
+// Anki uses the first 8 hex digits of the sha1 of the sort field (without html) to find duplicates
+fn checksum(field: &str) -> i64 {
+    let digest = sha1_smol::Sha1::from(strip_html(field)).digest().to_string();
+    i64::from_str_radix(&digest[..8], 16).unwrap_or(0)
+}
+
+// Deck ids are derived from the name so importing again adds to the same deck,
+// except "Default" which is Anki's built in deck with id 1
+fn deck_id(name: &str) -> i64 {
+    if name == "Default" {
+        return 1;
+    }
+    let digest = sha1_smol::Sha1::from(name).digest().to_string();
+    (1 << 30) + i64::from_str_radix(&digest[..7], 16).unwrap_or(0)
+}
+
+fn strip_html(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => stripped.push(c),
+            _ => {}
+        }
+    }
+    stripped
+}
+
+fn collection_conf(deck_id: i64) -> Value {
+    json!({
+        "activeDecks": [deck_id],
+        "curDeck": deck_id,
+        "newSpread": 0,
+        "collapseTime": 1200,
+        "timeLim": 0,
+        "estTimes": true,
+        "dueCounts": true,
+        "curModel": BASIC_MODEL_ID.to_string(),
+        "nextPos": 1,
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true,
+    })
+}
+
+fn basic_model(deck_id: i64, modified: i64) -> Value {
+    json!({
+        "id": BASIC_MODEL_ID,
+        "name": "Basic (md-to-anki)",
+        "type": 0,
+        "mod": modified,
+        "usn": -1,
+        "sortf": 0,
+        "did": deck_id,
+        "tags": [],
+        "vers": [],
+        "css": CSS,
+        "latexPre": LATEX_PRE,
+        "latexPost": LATEX_POST,
+        "req": [[0, "all", [0]]],
+        "flds": [field("Front", 0), field("Back", 1)],
+        "tmpls": [{
+            "name": "Card 1",
+            "ord": 0,
+            "qfmt": "{{Front}}",
+            "afmt": "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}",
+            "bqfmt": "",
+            "bafmt": "",
+            "did": null,
+            "bfont": "",
+            "bsize": 0,
+        }],
+    })
+}
+
+fn cloze_model(deck_id: i64, modified: i64) -> Value {
+    json!({
+        "id": CLOZE_MODEL_ID,
+        "name": "Cloze (md-to-anki)",
+        "type": 1,
+        "mod": modified,
+        "usn": -1,
+        "sortf": 0,
+        "did": deck_id,
+        "tags": [],
+        "vers": [],
+        "css": CSS.to_string() + CLOZE_CSS,
+        "latexPre": LATEX_PRE,
+        "latexPost": LATEX_POST,
+        "req": [[0, "all", [0]]],
+        "flds": [field("Text", 0), field("Back Extra", 1)],
+        "tmpls": [{
+            "name": "Cloze",
+            "ord": 0,
+            "qfmt": "{{cloze:Text}}",
+            "afmt": "{{cloze:Text}}<br>\n{{Back Extra}}",
+            "bqfmt": "",
+            "bafmt": "",
+            "did": null,
+            "bfont": "",
+            "bsize": 0,
+        }],
+    })
+}
+
+fn reversed_model(deck_id: i64, modified: i64) -> Value {
+    json!({
+        "id": REVERSED_MODEL_ID,
+        "name": "Basic (and reversed card) (md-to-anki)",
+        "type": 0,
+        "mod": modified,
+        "usn": -1,
+        "sortf": 0,
+        "did": deck_id,
+        "tags": [],
+        "vers": [],
+        "css": CSS,
+        "latexPre": LATEX_PRE,
+        "latexPost": LATEX_POST,
+        "req": [[0, "all", [0]], [1, "all", [1]]],
+        "flds": [field("Front", 0), field("Back", 1)],
+        "tmpls": [{
+            "name": "Card 1",
+            "ord": 0,
+            "qfmt": "{{Front}}",
+            "afmt": "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}",
+            "bqfmt": "",
+            "bafmt": "",
+            "did": null,
+            "bfont": "",
+            "bsize": 0,
+        }, {
+            "name": "Card 2",
+            "ord": 1,
+            "qfmt": "{{Back}}",
+            "afmt": "{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}",
+            "bqfmt": "",
+            "bafmt": "",
+            "did": null,
+            "bfont": "",
+            "bsize": 0,
+        }],
+    })
+}
+
+fn field(name: &str, ord: usize) -> Value {
+    json!({
+        "name": name,
+        "ord": ord,
+        "font": "Arial",
+        "size": 20,
+        "media": [],
+        "rtl": false,
+        "sticky": false,
+    })
+}
+
+fn deck(id: i64, name: &str, modified: i64) -> Value {
+    json!({
+        "id": id,
+        "name": name,
+        "desc": "",
+        "mod": modified,
+        "usn": -1,
+        "dyn": 0,
+        "conf": DEFAULT_CONF_ID,
+        "collapsed": false,
+        "browserCollapsed": false,
+        "extendNew": 10,
+        "extendRev": 50,
+        "newToday": [0, 0],
+        "revToday": [0, 0],
+        "lrnToday": [0, 0],
+        "timeToday": [0, 0],
+    })
+}
+
+fn deck_conf() -> Value {
+    json!({
+        "id": DEFAULT_CONF_ID,
+        "name": "Default",
+        "mod": 0,
+        "usn": 0,
+        "maxTaps": 8,
+        "timer": 0,
+        "autoplay": true,
+        "replayq": true,
+        "dyn": false,
+        "new": {
+            "bury": true,
+            "delays": [1, 10],
+            "initialFactor": 2500,
+            "ints": [1, 4, 7],
+            "order": 1,
+            "perDay": 20,
+            "separate": true,
+        },
+        "lapse": {
+            "delays": [10],
+            "leechAction": 0,
+            "leechFails": 8,
+            "minInt": 1,
+            "mult": 0,
+        },
+        "rev": {
+            "bury": true,
+            "ease4": 1.3,
+            "fuzz": 0.05,
+            "ivlFct": 1,
+            "maxIvl": 36500,
+            "minSpace": 1,
+            "perDay": 100,
+        },
+    })
+}
@@ -1,16 +1,76 @@
 use tui::style::{Color, Style};
-use tui::widgets::Text;
 use crate::parse::Attribute;
-use crate::card::Card;
+use crate::card::{self, Card, NoteType};
+use crate::editor::Editor;
+use crate::external::Fields;
+use crate::search::Query;
+
+// The most changes that are remembered to be undone
+const HISTORY_LIMIT: usize = 100;
+
+/// A question asked in the heading, and what the answer is used for
+pub enum Prompt {
+    /// Rename a tag on every card
+    Rename(String),
+    /// Merge a tag into another one that is already used
+    Merge(String),
+    /// Add a tag to the marked cards
+    Tag,
+    /// Replace the tags of the marked cards
+    Retag,
+    /// Move the marked cards to a subdeck of the export deck
+    Deck,
+}
+impl Prompt {
+    pub fn question(&self) -> String {
+        match self {
+            Prompt::Rename(tag) => format!("Rename `{}` to: ", tag),
+            Prompt::Merge(tag) => format!("Merge `{}` into: ", tag),
+            Prompt::Tag => "Add tag: ".to_string(),
+            Prompt::Retag => "Replace tags with: ".to_string(),
+            Prompt::Deck => "Move to subdeck of the export deck (empty for the export deck itself): ".to_string(),
+        }
+    }
+}
+
+/// The cards as they were before a change, so the change can be undone
+struct Snapshot {
+    /// What the change was, e.g. "delete `Question`"
+    description: String,
+    cards: Vec<Card>,
+    deleted: Vec<Card>,
+    last_selected: usize,
+}
 
 pub struct App {
     pub cards: Vec<Card>,
+    /// Cards from the markdown that were deleted, which are removed from it when saving
+    pub deleted: Vec<Card>,
+    undo_stack: Vec<Snapshot>,
+    redo_stack: Vec<Snapshot>,
     default_text: String,
     pub text: String,
     pub title: &'static str,
     pub is_editing: bool,
-    field_editing: Attribute,
-    pub cursor_location: usize,
+    pub field_editing: Attribute,
+    pub editor: Editor,
+    /// Whether a search is being typed
+    pub is_searching: bool,
+    /// The search, which stays after it is typed so `n` and `N` can move between the matches
+    pub search: Editor,
+    pub query: Query,
+    /// Where the search was started from, to go back to if it is cancelled
+    search_from: usize,
+    /// Whether the list only shows the cards that match the search
+    pub filtered: bool,
+    /// Whether the tag panel is shown in place of the selected card
+    pub tag_panel: bool,
+    pub tag_selected: usize,
+    /// The question being asked, if any, and the answer as it is typed
+    pub prompt: Option<Prompt>,
+    pub answer: Editor,
+    /// Where a range of cards being marked was started
+    pub range_from: Option<usize>,
     pub selected: Option<usize>,
     pub last_selected: usize,
     pub highlight_style: Style,
@@ -19,144 +79,644 @@ pub struct App {
 impl App {
 
     pub fn new(cards: Vec<Card>) -> App {
-        let text = "[q] quit | [f] edit front | [b] edit back | [t] edit tags | [n] new | [d] delete | [x] export";
+        let text = "[q] quit | [f] edit front | [b] edit back | [t] edit tags | [e] open in $EDITOR | [n] new | [d] delete | [J/K] move | [u] undo | [ctrl-r] redo | [/] search | [space/v/*] mark | [T] tags | [D] deck | [z] suspend | [s] save | [x/X] export all/marked";
         App {
             cards,
+            deleted: Vec::new(),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
             default_text: text.to_string(),
             text: text.to_string(),
             title: "Info",
             is_editing: false,
             field_editing: Attribute::Front,
-            cursor_location: 0,
+            editor: Editor::new(String::new()),
+            is_searching: false,
+            search: Editor::new(String::new()),
+            query: Query::default(),
+            search_from: 0,
+            filtered: false,
+            tag_panel: false,
+            tag_selected: 0,
+            prompt: None,
+            answer: Editor::new(String::new()),
+            range_from: None,
             selected: None,
             last_selected: 0,
             highlight_style: Style::default().fg(Color::Black).bg(Color::White),
         }
     }
 
+    // The cards shown in the list, which are those that match the search when filtering
+    pub fn visible(&self) -> Vec<usize> {
+        (0..self.cards.len())
+            .filter(|&i| !self.filtered || self.query.matches(&self.cards[i]))
+            .collect()
+    }
+
     // Select the next list item
     pub fn next(&mut self) {
-        let selected = if let Some(selected) = self.selected {
-            if selected >= self.cards.len() - 1 {
-                Some(0)
-            } else {
-                Some(selected + 1)
-            }
-        } else {
-            Some(0)
+        let visible = self.visible();
+        let selected = match self.selected.and_then(|s| visible.iter().position(|&i| i == s)) {
+            Some(position) => visible.get(position + 1).or(visible.first()),
+            None => visible.first(),
         };
-        self.selected = selected;
-        if let Some(s) = selected { self.last_selected = s }
+        self.select(selected.copied());
     }
 
     // Select the previous list item
     pub fn prev(&mut self) {
-        let selected = if let Some(selected) = self.selected {
-            if selected > 0 {
-                Some(selected - 1)
-            } else {
-                Some(self.cards.len() - 1)
-            }
+        let visible = self.visible();
+        let selected = match self.selected.and_then(|s| visible.iter().position(|&i| i == s)) {
+            Some(position) if position > 0 => visible.get(position - 1),
+            Some(_) => visible.last(),
+            None => visible.first(),
+        };
+        self.select(selected.copied());
+    }
+
+    fn select(&mut self, selected: Option<usize>) {
+        if let Some(s) = selected {
+            self.selected = selected;
+            self.last_selected = s;
+        }
+    }
+
+    // Begin typing a search. The list moves to the first match as it is typed
+    pub fn start_search(&mut self) {
+        self.is_searching = true;
+        self.search = Editor::new(String::new());
+        self.query = Query::default();
+        self.search_from = self.last_selected;
+        self.title = "Search: [enter] done | [esc] cancel | [ctrl-f] only list matches | tag:name deck:name";
+    }
+
+    // Search again after the search was changed, from where it was started
+    pub fn update_search(&mut self) {
+        self.query = Query::parse(&self.search.text);
+        self.select(Some(self.search_from).filter(|&s| s < self.cards.len()));
+        self.find_match(true, true);
+    }
+
+    // Stop typing the search, keeping it to move between the matches with `n` and `N`
+    pub fn finish_search(&mut self) {
+        self.is_searching = false;
+        self.title = "Info";
+        let matches = self.cards.iter().filter(|card| self.query.matches(card)).count();
+        self.text = if self.query.is_empty() {
+            self.default_text.clone()
         } else {
-            Some(0)
+            format!("{} matches for `{}` | [n/N] next/previous match | [ctrl-f] only list matches | [esc] clear search", matches, self.search.text.trim())
         };
-        self.selected = selected;
-        if let Some(s) = selected { self.last_selected = s }
     }
 
-    // Begin editing one of the fields of the selected card
-    pub fn enable_edit(&mut self, field_editing: Attribute) {
-        self.is_editing = true;
-        self.title = "Editing. Press <esc> when finished";
-        match field_editing {
-            Attribute::Front => {
-                self.cursor_location = self.cards[self.last_selected].front.len();
-                self.text = self.cards[self.last_selected].front.clone();
-                self.field_editing = Attribute::Front;
+    // Forget the search, going back to where it was started if it was still being typed
+    pub fn clear_search(&mut self) {
+        if self.is_searching {
+            self.select(Some(self.search_from).filter(|&s| s < self.cards.len()));
+        }
+        self.search = Editor::new(String::new());
+        self.query = Query::default();
+        self.finish_search();
+    }
+
+    // Only list the cards that match the search, or list every card again
+    pub fn toggle_filter(&mut self) {
+        self.filtered = !self.filtered;
+        let visible = self.visible();
+        if !visible.contains(&self.last_selected) {
+            self.select(visible.first().copied());
+        }
+    }
+
+    // Select the next card that matches the search, or the previous one, going round the list.
+    // The selected card counts as the next match if `here` is set, which is used while typing
+    pub fn find_match(&mut self, forward: bool, here: bool) {
+        if self.query.is_empty() || self.cards.is_empty() {
+            return;
+        }
+        let count = self.cards.len();
+        let start = if here { 0 } else { 1 };
+        let found = (start..count + start)
+            .map(|step| if forward { (self.last_selected + step) % count } else { (self.last_selected + count * 2 - step) % count })
+            .find(|&i| self.query.matches(&self.cards[i]));
+        match found {
+            Some(i) => self.select(Some(i)),
+            None if !self.is_searching => self.text = format!("No matches for `{}`", self.search.text.trim()),
+            None => {},
+        }
+    }
+
+    // Mark or unmark the selected card, then select the next one so a run of cards can be marked
+    pub fn toggle_mark(&mut self) {
+        if let Some(card) = self.cards.get_mut(self.last_selected) {
+            card.marked = !card.marked;
+            self.next();
+        }
+    }
+
+    // Start marking a range of cards from the selected one, or mark the range once its other
+    // end is selected
+    pub fn mark_range(&mut self) {
+        match self.range_from {
+            Some(_) => {
+                let range = self.range();
+                self.range_from = None;
+                for &i in &range {
+                    self.cards[i].marked = true;
+                }
+                self.text = format!("Marked {} cards", range.len());
             },
-            Attribute::Back => {
-                self.cursor_location = self.cards[self.last_selected].back.len();
-                self.text = self.cards[self.last_selected].back.clone();
-                self.field_editing = Attribute::Back;
+            None if self.last_selected < self.cards.len() => {
+                self.range_from = Some(self.last_selected);
+                self.text = "Marking a range: select the card at its other end and press [v], or [esc] to cancel".to_string();
             },
-            Attribute::MainTag | Attribute::SubTag => {
-                self.cursor_location = self.cards[self.last_selected].tags.len();
-                self.text = self.cards[self.last_selected].tags.clone();
-                self.field_editing = Attribute::MainTag;
+            None => {},
+        }
+    }
+
+    pub fn cancel_range(&mut self) {
+        self.range_from = None;
+        self.text = self.default_text.clone();
+    }
+
+    // The listed cards from where the range was started to the selected card
+    pub fn range(&self) -> Vec<usize> {
+        let from = match self.range_from {
+            Some(from) => from,
+            None => return Vec::new(),
+        };
+        let visible = self.visible();
+        let position = |card| visible.iter().position(|&i| i == card);
+        match (position(from), position(self.last_selected)) {
+            (Some(a), Some(b)) => visible[a.min(b)..=a.max(b)].to_vec(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Mark every card that matches the search, or every card if there is none. If they are all
+    // marked already they are unmarked instead
+    pub fn mark_matching(&mut self) {
+        let matching: Vec<usize> = (0..self.cards.len()).filter(|&i| self.query.matches(&self.cards[i])).collect();
+        let mark = !matching.iter().all(|&i| self.cards[i].marked);
+        for &i in &matching {
+            self.cards[i].marked = mark;
+        }
+        self.text = format!("{} {} cards", if mark { "Marked" } else { "Unmarked" }, matching.len());
+    }
+
+    pub fn clear_marks(&mut self) {
+        for card in &mut self.cards {
+            card.marked = false;
+        }
+        self.text = self.default_text.clone();
+    }
+
+    // The cards that changes to several cards apply to: the marked ones, or else the selected one
+    pub fn marked(&self) -> Vec<usize> {
+        let marked: Vec<usize> = (0..self.cards.len()).filter(|&i| self.cards[i].marked).collect();
+        if marked.is_empty() && self.last_selected < self.cards.len() {
+            vec![self.last_selected]
+        } else {
+            marked
+        }
+    }
+
+    // Every tag, with the number of cards that have it
+    pub fn tags(&self) -> Vec<(String, usize)> {
+        card::count_tags(&self.cards).into_iter().map(|(tag, count)| (tag.to_string(), count)).collect()
+    }
+
+    fn selected_tag(&self) -> Option<String> {
+        let tags = self.tags();
+        tags.get(self.tag_selected.min(tags.len().saturating_sub(1))).map(|(tag, _)| tag.clone())
+    }
+
+    // Show the tag panel in place of the selected card, or hide it again
+    pub fn toggle_tag_panel(&mut self) {
+        self.tag_panel = !self.tag_panel;
+        self.text = if self.tag_panel {
+            "[↑/↓] select tag | [enter] list its cards | [r] rename | [m] merge into another tag | [a] add a tag to the marked cards | [d] remove it from the marked cards | [esc] close".to_string()
+        } else {
+            self.default_text.clone()
+        };
+    }
+
+    // Select the next tag in the tag panel, or the previous one, going round the list
+    pub fn move_tag(&mut self, down: bool) {
+        let count = self.tags().len().max(1);
+        let selected = self.tag_selected.min(count - 1);
+        self.tag_selected = if down { (selected + 1) % count } else { (selected + count - 1) % count };
+    }
+
+    // Only list the cards with the selected tag, by searching for it
+    pub fn filter_by_tag(&mut self) {
+        if let Some(tag) = self.selected_tag() {
+            self.toggle_tag_panel();
+            self.search = Editor::new(format!("tag:{}", tag));
+            self.query = Query::parse(&self.search.text);
+            self.filtered = false;
+            self.toggle_filter();
+            self.finish_search();
+        }
+    }
+
+    // Ask a question in the heading. Renaming starts from the name the tag has now
+    pub fn start_prompt(&mut self, prompt: Prompt) {
+        let selected = self.cards.get(self.last_selected);
+        let answer = match &prompt {
+            Prompt::Rename(tag) => tag.clone(),
+            Prompt::Retag => selected.map(Card::tag_text).unwrap_or_default(),
+            Prompt::Deck => selected.and_then(|card| card.deck.clone()).unwrap_or_default(),
+            _ => String::new(),
+        };
+        self.answer = Editor::new(answer);
+        self.prompt = Some(prompt);
+        self.title = "[enter] done | [esc] cancel";
+    }
+
+    // Ask for a tag to be renamed or merged into another, if there is one selected
+    pub fn start_tag_prompt(&mut self, merge: bool) {
+        if let Some(tag) = self.selected_tag() {
+            self.start_prompt(if merge { Prompt::Merge(tag) } else { Prompt::Rename(tag) });
+        }
+    }
+
+    pub fn cancel_prompt(&mut self) {
+        self.prompt = None;
+        self.title = "Info";
+    }
+
+    // Do what the question was asked for with the answer that was typed
+    pub fn answer_prompt(&mut self) {
+        let prompt = match self.prompt.take() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        self.title = "Info";
+        let answer = self.answer.text.trim().to_string();
+        let is_tag = matches!(prompt, Prompt::Rename(_) | Prompt::Merge(_) | Prompt::Tag);
+        if is_tag && (answer.is_empty() || answer.contains(char::is_whitespace)) {
+            self.text = "error: tags can't be empty or contain spaces".to_string();
+            return;
+        }
+        self.text = match prompt {
+            Prompt::Rename(tag) => {
+                let count = self.rename_tag(&tag, &answer, format!("rename tag `{}` to `{}`", tag, answer));
+                format!("Renamed `{}` to `{}` on {} cards", tag, answer, count)
+            },
+            Prompt::Merge(tag) if self.tags().iter().any(|(t, _)| *t == answer) && tag != answer => {
+                let count = self.rename_tag(&tag, &answer, format!("merge tag `{}` into `{}`", tag, answer));
+                format!("Merged `{}` into `{}` on {} cards", tag, answer, count)
+            },
+            Prompt::Merge(_) => format!("error: there is no other tag called `{}` to merge into", answer),
+            Prompt::Tag => {
+                let marked = self.marked();
+                self.record(format!("add tag `{}`", answer));
+                for &i in &marked {
+                    self.cards[i].add_tag(&answer);
+                }
+                format!("Added `{}` to {} cards", answer, marked.len())
+            },
+            Prompt::Retag => {
+                let marked = self.marked();
+                self.record(format!("replace the tags of {} cards", marked.len()));
+                for &i in &marked {
+                    self.cards[i].set_tags(&answer);
+                }
+                format!("Replaced the tags of {} cards", marked.len())
+            },
+            Prompt::Deck => {
+                let marked = self.marked();
+                self.record(format!("move {} cards to another subdeck", marked.len()));
+                let deck = Some(answer).filter(|deck| !deck.is_empty());
+                for &i in &marked {
+                    self.cards[i].deck = deck.clone();
+                }
+                format!("Moved {} cards to {}", marked.len(), deck.map_or("the export deck".to_string(), |d| format!("subdeck `{}`", d)))
+            },
+        };
+    }
+
+    // Rename a tag on every card, returning how many cards had it
+    fn rename_tag(&mut self, from: &str, to: &str, description: String) -> usize {
+        self.record(description);
+        self.cards.iter_mut().map(|card| card.rename_tag(from, to)).filter(|&renamed| renamed).count()
+    }
+
+    // Remove the selected tag from the marked cards
+    pub fn remove_tag(&mut self) {
+        let tag = match self.selected_tag() {
+            Some(tag) => tag,
+            None => return,
+        };
+        self.record(format!("remove tag `{}`", tag));
+        let mut count = 0;
+        for i in self.marked() {
+            if self.cards[i].remove_tag(&tag) {
+                count += 1;
             }
         }
+        self.text = format!("Removed `{}` from {} cards", tag, count);
+    }
+
+    // Remember the cards as they are before a change, so it can be undone
+    pub fn record(&mut self, description: String) {
+        let snapshot = self.snapshot(description);
+        self.undo_stack.push(snapshot);
+        if self.undo_stack.len() > HISTORY_LIMIT {
+            self.undo_stack.remove(0);
+        }
+        self.redo_stack.clear();
+    }
+
+    // Undo the last change, saying what it was in the status line
+    pub fn undo(&mut self) {
+        self.text = match self.undo_stack.pop() {
+            Some(snapshot) => {
+                let redo = self.snapshot(snapshot.description.clone());
+                self.redo_stack.push(redo);
+                format!("Undid {}", self.restore(snapshot))
+            },
+            None => "Nothing to undo".to_string(),
+        };
+    }
+
+    // Redo the last change that was undone
+    pub fn redo(&mut self) {
+        self.text = match self.redo_stack.pop() {
+            Some(snapshot) => {
+                let undo = self.snapshot(snapshot.description.clone());
+                self.undo_stack.push(undo);
+                format!("Redid {}", self.restore(snapshot))
+            },
+            None => "Nothing to redo".to_string(),
+        };
+    }
+
+    // Forget every change. Saving does this, as the cards then point at the new markdown
+    pub fn clear_history(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+
+    fn snapshot(&self, description: String) -> Snapshot {
+        Snapshot {
+            description,
+            cards: self.cards.clone(),
+            deleted: self.deleted.clone(),
+            last_selected: self.last_selected,
+        }
     }
 
-    // Stop editing the selected card
+    // Put the cards back how they were, returning what the change was
+    fn restore(&mut self, snapshot: Snapshot) -> String {
+        self.cards = snapshot.cards;
+        self.deleted = snapshot.deleted;
+        self.last_selected = snapshot.last_selected.min(self.cards.len().saturating_sub(1));
+        self.selected = Some(self.last_selected);
+        snapshot.description
+    }
+
+    // Move the selected card up or down the list, which is the order the cards are exported in.
+    // It swaps places with the next card that is shown, skipping any the filter is hiding
+    pub fn move_card(&mut self, down: bool) {
+        let visible = self.visible();
+        let from = self.last_selected;
+        let position = match visible.iter().position(|&i| i == from) {
+            Some(position) => position,
+            None => return,
+        };
+        let neighbour = if down { position + 1 } else { position.wrapping_sub(1) };
+        let to = match visible.get(neighbour) {
+            Some(&to) => to,
+            None => return,
+        };
+        let direction = if down { "down" } else { "up" };
+        self.record(format!("move {} {}", card_name(&self.cards[from]), direction));
+        self.cards.swap(from, to);
+        self.selected = Some(to);
+        self.last_selected = to;
+    }
+
+    // Add a new card after the selected one, in the same list of the same document
+    pub fn new_card(&mut self) {
+        self.record("new card".to_string());
+        let mut card = Card::new("New Card".to_string(), "".to_string(), Vec::new());
+        if let Some(selected) = self.cards.get(self.last_selected) {
+            card.tags = selected.tags.clone();
+            card.deck = selected.deck.clone();
+            card.note_type = selected.note_type;
+            card.path = selected.path.clone();
+            card.span = selected.span.as_ref().map(|span| span.end..span.end);
+            card.syntax = selected.syntax.clone();
+            card.edited = true;
+            // Give the card whatever it needs to be read back as a card of the same kind
+            if card.note_type == NoteType::Cloze {
+                card.front = "New {{c1::Card}}".to_string();
+            } else {
+                card.add_to_back("New Answer");
+            }
+        }
+        let index = (self.last_selected + 1).min(self.cards.len());
+        self.cards.insert(index, card);
+        self.selected = Some(index);
+        self.last_selected = index;
+    }
+
+    // Delete the marked cards, or the selected card if none are marked, remembering them so
+    // they can be removed from the markdown. Marked cards hidden by the filter are kept
+    pub fn delete_card(&mut self) {
+        let visible = self.visible();
+        let (marked, hidden): (Vec<usize>, Vec<usize>) = self.marked().into_iter()
+            .partition(|i| visible.contains(i));
+        if !hidden.is_empty() {
+            self.text = format!("Kept {} marked cards hidden by the filter", hidden.len());
+        }
+        match marked[..] {
+            [] => return,
+            [only] => self.record(format!("delete {}", card_name(&self.cards[only]))),
+            _ => self.record(format!("delete {} cards", marked.len())),
+        }
+        for &i in marked.iter().rev() {
+            let card = self.cards.remove(i);
+            if card.span.as_ref().is_some_and(|span| !span.is_empty()) {
+                self.deleted.push(card);
+            }
+        }
+        // Select the card that took the place of the first one deleted
+        let first = marked[0].min(self.cards.len().saturating_sub(1));
+        self.selected = Some(first);
+        self.last_selected = first;
+    }
+
+    // Suspend the marked cards, or unsuspend them if they all are already
+    pub fn toggle_suspended(&mut self) {
+        let marked = self.marked();
+        if marked.is_empty() {
+            return;
+        }
+        let suspend = !marked.iter().all(|&i| self.cards[i].suspended);
+        let action = if suspend { "suspend" } else { "unsuspend" };
+        self.record(format!("{} {} cards", action, marked.len()));
+        for &i in &marked {
+            self.cards[i].suspended = suspend;
+        }
+        self.text = format!("{}ed {} cards", if suspend { "Suspend" } else { "Unsuspend" }, marked.len());
+    }
+
+    // Replace the fields of the selected card with the ones edited in `$EDITOR`
+    pub fn replace_fields(&mut self, fields: Fields) {
+        self.record(format!("edit {}", card_name(&self.cards[self.last_selected])));
+        let card = &mut self.cards[self.last_selected];
+        card.front = fields.front;
+        card.back = fields.back;
+        card.tags = fields.tags;
+        card.edited = true;
+    }
+
+    // Begin editing one of the fields of the selected card. Each `<br>` separated line of the
+    // front and back is a line in the editor. Does nothing when there are no cards
+    pub fn enable_edit(&mut self, field_editing: Attribute) {
+        if self.last_selected >= self.cards.len() {
+            return;
+        }
+        self.is_editing = true;
+        self.title = "Editing. Press <esc> when finished";
+        self.text = "[esc] finish | [enter] new line | [home/end] start/end of line | [alt-b/alt-f] previous/next word".to_string();
+        let card = &self.cards[self.last_selected];
+        let text = match field_editing {
+            Attribute::Front => card::field_text(&card.front),
+            Attribute::Back => card::field_text(&card.back),
+            Attribute::MainTag => card.tag_text(),
+        };
+        self.editor = Editor::new(text);
+        self.field_editing = field_editing;
+    }
+
+    // Stop editing the selected card, turning the lines of the editor back into `<br>`s
     pub fn disable_edit(mut self) -> App {
         self.is_editing = false;
         self.title = "Info";
-        match self.field_editing {
-            Attribute::Front => { self.cards[self.last_selected].front = self.text; },
-            Attribute::Back => { self.cards[self.last_selected].back = self.text; },
-            Attribute::MainTag => { self.cards[self.last_selected].tags = self.text; },
-            _ => {}
+        let card = &self.cards[self.last_selected];
+        let text = &self.editor.text;
+        // The field as it was is put through the editor too, so that only real changes count
+        let (old, new, field) = match self.field_editing {
+            Attribute::Front => (card::text_field(&card::field_text(&card.front), false), card::text_field(text, false), "front"),
+            Attribute::Back => (card::text_field(&card::field_text(&card.back), true), card::text_field(text, true), "back"),
+            Attribute::MainTag => (card.tag_text(), text.split_whitespace().collect::<Vec<_>>().join(" "), "tags"),
+        };
+        if old != new {
+            self.record(format!("edit {} of {}", field, card_name(card)));
+            let card = &mut self.cards[self.last_selected];
+            match self.field_editing {
+                Attribute::Front => { card.front = new; },
+                Attribute::Back => { card.back = new; },
+                Attribute::MainTag => { card.set_tags(&new); },
+            }
+            card.edited = true;
         }
 
         self.text = self.default_text.clone();
         self
     }
+}
 
-    // Moveing the cursor while editing
-    pub fn move_cursor_left(&mut self) {
-        if self.cursor_location != 0 { self.cursor_location -= 1; }
+// How a card is referred to in the status line: its front, shortened if it is long
+fn card_name(card: &Card) -> String {
+    let front: String = card.front.chars().take(30).collect();
+    if front.len() < card.front.len() {
+        format!("`{}...`", front)
+    } else {
+        format!("`{}`", front)
     }
-    pub fn move_cursor_right(&mut self) {
-        if self.cursor_location != self.text.len() { self.cursor_location += 1; }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(fronts: &[&str]) -> App {
+        let cards = fronts.iter().map(|front| Card::new(front.to_string(), String::new(), Vec::new())).collect();
+        App::new(cards)
     }
 
-    // Adding a character to the text being edited at the cursor location
-    pub fn push(&mut self, c: char) {
-        self.text.insert(self.cursor_location, c);
-        self.cursor_location += 1;
+    fn fronts(app: &App) -> Vec<&str> {
+        app.cards.iter().map(|card| card.front.as_str()).collect()
     }
 
-    // Removing a character from the text at the cursor location
-    pub fn pop(&mut self) {
-        if self.cursor_location > 0 {
-            self.text.remove(self.cursor_location-1);
-            self.cursor_location -= 1;
-        }
+    #[test]
+    fn undo_and_redo() {
+        let mut app = app(&["One", "Two"]);
+        app.delete_card();
+        assert_eq!(fronts(&app), ["Two"]);
+        assert_eq!(app.deleted.len(), 0);
+        app.undo();
+        assert_eq!(app.text, "Undid delete `One`");
+        assert_eq!(fronts(&app), ["One", "Two"]);
+        app.redo();
+        assert_eq!(app.text, "Redid delete `One`");
+        assert_eq!(fronts(&app), ["Two"]);
+        app.undo();
+        // A new change can't be redone past
+        app.move_card(true);
+        assert_eq!(fronts(&app), ["Two", "One"]);
+        app.redo();
+        assert_eq!(app.text, "Nothing to redo");
+        assert_eq!(fronts(&app), ["Two", "One"]);
+        app.undo();
+        assert_eq!(fronts(&app), ["One", "Two"]);
+        app.undo();
+        assert_eq!(app.text, "Nothing to undo");
     }
 
-    // Returns an array of Text. The first element is the raw text before the cursor, the second
-    // is the styled character at the cursor location, and the last is the raw text after the cursor.
-    pub fn get_formatted_text(&self) -> [tui::widgets::Text;3] {
-        let cursor_style = Style::default().fg(Color::Black).bg(Color::White);
-        if self.text.is_empty() {
-            return [Text::raw(""),Text::styled(" ", cursor_style), Text::raw("")]
+    #[test]
+    fn history_is_limited() {
+        let mut app = app(&["One"]);
+        for _ in 0..HISTORY_LIMIT + 5 {
+            app.new_card();
         }
-        if !self.is_editing {
-            // If not editing, just return the text
-            [Text::raw(&self.text),Text::raw(""), Text::raw("")]
-        } else {
-            // If cursor is at start of line:
-            if self.cursor_location == 0 {
-                [
-                Text::styled(&self.text[..1], cursor_style),
-                Text::raw(&self.text[1..]),
-                Text::raw("")
-                ]
-            // If cursor is at end of line:
-            } else if self.cursor_location >= self.text.len() {
-                [
-                Text::raw(&self.text),
-                Text::styled(" ", cursor_style),
-                Text::raw("")
-                ]
-            // If cursor is anywhere inbetween:
-            } else {
-                [
-                Text::raw(&self.text[..self.cursor_location]),
-                Text::styled(&self.text[self.cursor_location..=self.cursor_location], cursor_style),
-                Text::raw(&self.text[self.cursor_location+1..])
-                ]
-            }
+        for _ in 0..HISTORY_LIMIT {
+            app.undo();
         }
+        // The oldest changes were forgotten
+        assert_eq!(app.cards.len(), 6);
+        app.undo();
+        assert_eq!(app.text, "Nothing to undo");
+        assert_eq!(app.cards.len(), 6);
+    }
+
+    #[test]
+    fn move_skips_cards_hidden_by_the_filter() {
+        let mut app = app(&["One", "Two", "Three", "Four"]);
+        app.query = Query::parse("o");
+        app.toggle_filter();
+        assert_eq!(app.visible(), [0, 1, 3]);
+        app.select(Some(3));
+        app.move_card(false);
+        assert_eq!(fronts(&app), ["One", "Four", "Three", "Two"]);
+        assert_eq!(app.last_selected, 1);
+        app.move_card(false);
+        assert_eq!(fronts(&app), ["Four", "One", "Three", "Two"]);
+        // Nothing is shown above it
+        app.move_card(false);
+        assert_eq!(fronts(&app), ["Four", "One", "Three", "Two"]);
+    }
+
+    #[test]
+    fn delete_keeps_marked_cards_hidden_by_the_filter() {
+        let mut app = app(&["One", "Two", "Three"]);
+        app.cards[0].marked = true;
+        app.cards[2].marked = true;
+        app.query = Query::parse("t");
+        app.toggle_filter();
+        app.delete_card();
+        assert_eq!(fronts(&app), ["One", "Two"]);
+        assert_eq!(app.text, "Kept 1 marked cards hidden by the filter");
+        // Without the filter every marked card goes
+        app.toggle_filter();
+        app.cards[1].marked = true;
+        app.delete_card();
+        assert!(app.cards.is_empty());
     }
 }
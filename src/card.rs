@@ -1,31 +1,305 @@
+use std::collections::BTreeMap;
+
+/// The Anki note type a card is exported as
+#[derive(PartialEq, Clone, Copy)]
+pub enum NoteType {
+    /// A front and a back
+    Basic,
+    /// Text containing `{{c1::...}}` deletions, with any extra information on the back
+    Cloze,
+    /// A front and a back, asked both ways round
+    BasicAndReversed,
+}
+impl NoteType {
+    // The name of the note type in a new Anki collection
+    pub fn name(self) -> &'static str {
+        match self {
+            NoteType::Basic => "Basic",
+            NoteType::Cloze => "Cloze",
+            NoteType::BasicAndReversed => "Basic (and reversed card)",
+        }
+    }
+}
+
+/// How a card is written in the markdown, so edits can be written back the same way
+#[derive(PartialEq, Clone)]
+pub enum Syntax {
+    /// An item of a list beneath a `[](question)` or `[](cloze)` tag
+    Item,
+    /// An item beneath a `[](definition)` tag. Reversed definitions have the definition on the front
+    Definition { reversed: bool },
+    /// A line split in two by a separator, e.g. `Question::Answer`
+    Inline { separator: String },
+}
+
+/// A line of the back of a card
+pub enum Line {
+    Text(String),
+    /// A fenced code block, which is kept whole
+    Code { language: String, code: String },
+}
+
+/// The fields that are hashed to make the GUID of a card without an id marker
+#[derive(PartialEq, Clone, Copy, Default, clap::ValueEnum)]
+pub enum GuidSource {
+    #[default]
+    Front,
+    FrontBack,
+    FrontTags,
+}
+
 /// A struct that represents an Anki card
+#[derive(Clone)]
 pub struct Card {
     pub front: String,
     pub back: String,
-    pub tags: String,
+    /// The Anki tags of the card. Hierarchical tags are separated by `::`, e.g. `Bio::Cells`
+    pub tags: Vec<String>,
+    pub note_type: NoteType,
+    /// The subdeck of the export deck the card goes in, e.g. `Chapter_1::Cells`
+    pub deck: Option<String>,
+    /// The id given to the card by an `<!-- id: ... -->` marker in the markdown
+    pub id: Option<String>,
+    /// The markdown file the card came from
+    pub path: Option<std::path::PathBuf>,
+    /// Where the card was written in the markdown, if it came from there. New cards that
+    /// haven't been saved yet have an empty span where they will be inserted
+    pub span: Option<std::ops::Range<usize>>,
+    /// How the card was written in the markdown
+    pub syntax: Option<Syntax>,
+    /// Whether the card has been changed since it was read, and needs writing back
+    pub edited: bool,
+    /// Whether the card is marked in `edit`, to change it along with the other marked cards
+    pub marked: bool,
+    /// Whether the card is exported suspended, so Anki doesn't show it until it is unsuspended
+    pub suspended: bool,
 }
 impl std::fmt::Display for Card {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f,
             "|{}|\n|{}|\ntags: {}",
-            self.front, self.back, self.tags
+            self.front, self.back, self.tag_text()
         )
     }
 }
 impl Card {
-    pub fn new(front: String, back: String, tags: String) -> Card {
+    pub fn new(front: String, back: String, tags: Vec<String>) -> Card {
         Card {
             front,
             back,
             tags,
+            note_type: NoteType::Basic,
+            deck: None,
+            id: None,
+            path: None,
+            span: None,
+            syntax: None,
+            edited: false,
+            marked: false,
+            suspended: false,
         }
     }
     // Add an additional line of text to the back of the card
     pub fn add_to_back(&mut self, text: &str) {
         self.back += &(text.to_owned() + "<br>");
     }
-    // Return a string that Anki can import
-    pub fn export(&self) -> String {
-        format!("{};{};{}\n",self.front,self.back,self.tags)
+    // Add a fenced code block to the back of the card. It goes on lines of its own, with a
+    // fence longer than any run of backticks in the code, so it stays intact
+    pub fn add_code_to_back(&mut self, language: &str, code: &str) {
+        self.back += &format!("\n{}\n", fenced(language, code));
+    }
+    pub fn add_line_to_back(&mut self, line: Line) {
+        match line {
+            Line::Text(text) => self.add_to_back(&text),
+            Line::Code { language, code } => self.add_code_to_back(&language, &code),
+        }
+    }
+    // Split the back into the lines and code blocks it was made from
+    pub fn back_lines(&self) -> Vec<Line> {
+        field_lines(&self.back)
+    }
+    // Return the numbers of the cloze deletions in the front, each of which becomes its own card
+    pub fn cloze_numbers(&self) -> Vec<usize> {
+        cloze_numbers(&self.front)
+    }
+    // The tags separated by spaces, the way Anki writes them
+    pub fn tag_text(&self) -> String {
+        self.tags.join(" ")
+    }
+    // Replace the tags with the ones in some text, separated by whitespace
+    pub fn set_tags(&mut self, text: &str) {
+        self.tags.clear();
+        for tag in text.split_whitespace() {
+            self.add_tag(tag);
+        }
+    }
+    pub fn add_tag(&mut self, tag: &str) {
+        if !self.tags.iter().any(|t| t == tag) {
+            self.tags.push(tag.to_string());
+        }
+    }
+    // Remove a tag and the tags inside it, returning whether there were any
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let count = self.tags.len();
+        self.tags.retain(|t| !is_inside(t, tag));
+        self.tags.len() != count
+    }
+    // Rename a tag, and the start of the tags inside it, returning whether there were any.
+    // Renaming a tag to one the card already has merges them
+    pub fn rename_tag(&mut self, from: &str, to: &str) -> bool {
+        if !self.tags.iter().any(|t| is_inside(t, from)) {
+            return false;
+        }
+        let tags = std::mem::take(&mut self.tags);
+        for tag in tags {
+            if is_inside(&tag, from) {
+                self.add_tag(&(to.to_string() + &tag[from.len()..]));
+            } else {
+                self.add_tag(&tag);
+            }
+        }
+        true
+    }
+    // Return the GUID Anki uses to recognise the card when it is imported again. This is the id
+    // from the markdown if there is one, or otherwise a hash of the fields picked by `source`
+    pub fn guid(&self, source: GuidSource) -> String {
+        if let Some(id) = &self.id {
+            return id.clone();
+        }
+        let hashed = match source {
+            GuidSource::Front => self.front.clone(),
+            GuidSource::FrontBack => self.front.clone() + "\x1f" + &self.back,
+            GuidSource::FrontTags => self.front.clone() + "\x1f" + &self.tag_text(),
+        };
+        sha1_smol::Sha1::from(hashed).digest().to_string()[..16].to_string()
+    }
+}
+
+// A fenced code block, with a fence longer than any run of backticks in the code
+fn fenced(language: &str, code: &str) -> String {
+    let longest = code.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    let newline = if code.ends_with('\n') { "" } else { "\n" };
+    format!("{}{}\n{}{}{}", fence, language, code, newline, fence)
+}
+
+// The text of a field as it is edited, with a line for each `<br>` separated line
+pub fn field_text(field: &str) -> String {
+    field_lines(field).into_iter()
+        .map(|line| match line {
+            Line::Text(text) => text,
+            Line::Code { language, code } => fenced(&language, &code),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Turn edited text back into a field, the opposite of `field_text`. Backs end every line
+// with `<br>`, as they do when they are parsed, while fronts only have them between lines
+pub fn text_field(text: &str, is_back: bool) -> String {
+    let mut card = Card::new(String::new(), String::new(), Vec::new());
+    for line in field_lines(text) {
+        card.add_line_to_back(line);
+    }
+    if is_back {
+        card.back
+    } else {
+        card.back.trim_end_matches("<br>").trim().to_string()
+    }
+}
+
+// Whether a tag is another one, or a tag inside it, like `Bio::Cells` is inside `Bio`
+fn is_inside(tag: &str, parent: &str) -> bool {
+    tag.strip_prefix(parent).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+// The number of cards with each tag
+pub fn count_tags(cards: &[Card]) -> BTreeMap<&str, usize> {
+    let mut per_tag = BTreeMap::new();
+    for card in cards {
+        for tag in &card.tags {
+            *per_tag.entry(tag.as_str()).or_insert(0) += 1;
+        }
+    }
+    per_tag
+}
+
+// Split a field into its `<br>` separated lines and fenced code blocks
+pub fn field_lines(field: &str) -> Vec<Line> {
+    let mut lines = Vec::new();
+    // The fence and language of the code block being read, and its code so far
+    let mut code_block: Option<(&str, &str, String)> = None;
+    for line in field.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        match code_block.as_mut() {
+            Some((fence, language, code)) => {
+                if trimmed == *fence {
+                    lines.push(Line::Code { language: language.to_string(), code: std::mem::take(code) });
+                    code_block = None;
+                } else {
+                    code.push_str(line);
+                }
+            },
+            None if trimmed.starts_with("```") => {
+                let backticks = trimmed.len() - trimmed.trim_start_matches('`').len();
+                code_block = Some((&trimmed[..backticks], trimmed[backticks..].trim(), String::new()));
+            },
+            None => {
+                lines.extend(trimmed.split("<br>")
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty())
+                    .map(|l| Line::Text(l.to_string())));
+            },
+        }
+    }
+    // A block that was never closed is kept as text
+    if let Some((fence, language, code)) = code_block {
+        lines.push(Line::Text(format!("{}{}", fence, language)));
+        lines.extend(code.lines().map(|l| Line::Text(l.to_string())));
+    }
+    lines
+}
+
+// Return the sorted, distinct numbers of the `{{cN::...}}` deletions in some text
+pub fn cloze_numbers(text: &str) -> Vec<usize> {
+    let mut numbers = Vec::new();
+    for part in text.split("{{c").skip(1) {
+        if let Some(end) = part.find("::") {
+            if let Ok(n) = part[..end].parse::<usize>() {
+                if n > 0 && !numbers.contains(&n) {
+                    numbers.push(n);
+                }
+            }
+        }
+    }
+    numbers.sort_unstable();
+    numbers
+}
+
+// Show the text of a cloze card with deletion `number` hidden as `[...]`, or its hint, and the
+// other deletions shown as normal text. If `reveal` is set the deletion is shown in brackets
+pub fn cloze_text(text: &str, number: usize, reveal: bool) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{c") {
+        let deletion = &rest[start + 3..];
+        let (n, content, end) = match (deletion.find("::"), deletion.find("}}")) {
+            (Some(separator), Some(end)) if separator < end => {
+                (deletion[..separator].parse::<usize>().ok(), &deletion[separator + 2..end], end)
+            },
+            _ => break,
+        };
+        let (answer, hint) = match content.find("::") {
+            Some(i) => (&content[..i], Some(&content[i + 2..])),
+            None => (content, None),
+        };
+        output += &rest[..start];
+        output += &match (n == Some(number), reveal) {
+            (false, _) => answer.to_string(),
+            (true, true) => format!("[{}]", answer),
+            (true, false) => format!("[{}]", hint.unwrap_or("...")),
+        };
+        rest = &deletion[end + 2..];
     }
+    output + rest
 }
@@ -0,0 +1,41 @@
+//! Checks for cards that parse fine but probably aren't what was intended
+use crate::card::{Card, NoteType};
+use crate::media;
+
+use std::collections::HashMap;
+
+/// Something suspicious about one of the cards
+pub struct Warning {
+    /// The index of the card the warning is about
+    pub card: usize,
+    pub message: String,
+}
+
+// Look through the cards for empty fields, duplicates and missing media
+pub fn check(cards: &[Card]) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut fronts: HashMap<&str, usize> = HashMap::new();
+    let mut ids: HashMap<&str, usize> = HashMap::new();
+
+    for (i, card) in cards.iter().enumerate() {
+        let mut warn = |message: String| warnings.push(Warning { card: i, message });
+        if card.front.trim().is_empty() {
+            warn("card has an empty front".to_string());
+        }
+        if card.back.trim().is_empty() && card.note_type != NoteType::Cloze {
+            warn(format!("card `{}` has an empty back", card.front));
+        }
+        if let Some(first) = fronts.insert(&card.front, i) {
+            warn(format!("card `{}` has the same front as card {}", card.front, first + 1));
+        }
+        for path in media::missing(card) {
+            warn(format!("card `{}` refers to `{}`, which doesn't exist", card.front, path.display()));
+        }
+        if let Some(id) = &card.id {
+            if let Some(first) = ids.insert(id, i) {
+                warn(format!("card `{}` has the same id as card {}", card.front, first + 1));
+            }
+        }
+    }
+    warnings
+}
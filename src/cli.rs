@@ -0,0 +1,119 @@
+//! The command line arguments
+use crate::card::GuidSource;
+use crate::export::Format;
+use crate::parse::TagStyle;
+use crate::text::Delimiter;
+
+use clap::{Args, Parser, Subcommand};
+use std::fmt;
+
+/// Convert markdown notes into Anki flashcards
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Edit the cards in the terminal interface, then export them with `x`
+    Edit(ExportArgs),
+    /// Export the cards straight away without opening the interface
+    Convert(ExportArgs),
+    /// Check the documents for errors and suspicious cards without exporting anything
+    Lint {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Fail if there are any warnings as well as errors
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Study the cards in the terminal, scheduling them with a simple SM-2 style scheduler
+    Study {
+        #[command(flatten)]
+        input: InputArgs,
+        /// The file the schedule is kept in. Defaults to the first input, or the directory a glob
+        /// pattern starts from, with the extension `study.json`
+        #[arg(long)]
+        schedule: Option<std::path::PathBuf>,
+        /// Study every card, not just the ones that are due
+        #[arg(long)]
+        all: bool,
+    },
+    /// Show how many cards there are for each tag and note type
+    Stats {
+        #[command(flatten)]
+        input: InputArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct InputArgs {
+    /// The markdown files to read cards from. Directories are searched for markdown files
+    /// recursively, and glob patterns such as `notes/**/*.md` are expanded
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    /// Tag each card with the directories and name of the file it came from
+    #[arg(long)]
+    pub path_tags: bool,
+    /// Put the cards from each file in a subdeck named after its directories and name
+    #[arg(long)]
+    pub path_decks: bool,
+    /// Put each card in a subdeck named after the headings it is under, e.g. `Main::Sub::Subsub`
+    #[arg(long)]
+    pub heading_decks: bool,
+    /// How the headings a card is under are turned into its tags
+    #[arg(long, value_enum, default_value_t = TagStyle::Hierarchical)]
+    pub tags: TagStyle,
+    /// What the GUIDs of cards without an id are made from
+    #[arg(long, value_enum, default_value_t = GuidSource::Front)]
+    pub guid_from: GuidSource,
+    /// What splits a line such as `Question?::Answer` anywhere in the document into a card.
+    /// An empty separator turns these cards off
+    #[arg(long, default_value = "::")]
+    pub inline_separator: String,
+    /// What splits a line such as `Term ::: Definition` into a card asked both ways round.
+    /// An empty separator turns these cards off
+    #[arg(long, default_value = ":::")]
+    pub reversed_separator: String,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    /// Add a permanent id to every card in the markdown files that doesn't have one
+    #[arg(long)]
+    pub write_ids: bool,
+    /// Where to write the cards, or `-` for stdout when converting. Defaults to the name of the first input
+    #[arg(short, long)]
+    pub output: Option<String>,
+    /// The kind of file to export. Defaults to `apkg` if the output ends in `.apkg`, otherwise `text`
+    #[arg(short, long, value_enum)]
+    pub format: Option<Format>,
+    /// What separates the fields of text files
+    #[arg(long, value_enum, default_value_t = Delimiter::Semicolon)]
+    pub delimiter: Delimiter,
+    /// The deck to put the cards in. Defaults to the name of the output file
+    #[arg(short, long)]
+    pub deck: Option<String>,
+    /// Copy the images and audio used by text exports into this folder, usually the
+    /// `collection.media` folder of an Anki profile. Packages always include their media
+    #[arg(long)]
+    pub media_dir: Option<std::path::PathBuf>,
+    /// Export the fields exactly as written instead of converting their markdown to HTML
+    #[arg(long)]
+    pub raw: bool,
+}
+
+/// A problem with the arguments, such as an input that matches no files, rather than with the
+/// documents themselves
+#[derive(Debug)]
+pub struct UsageError(pub String);
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl failure::Fail for UsageError {}
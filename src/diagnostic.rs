@@ -0,0 +1,60 @@
+//! Printing parse errors and warnings in the style of rustc, pointing at the offending line
+use crate::card::{Card, Syntax};
+use crate::check::Warning;
+use crate::parse::{to_position, ParseError};
+use crate::source::Document;
+
+// Format an error along with the line of the document it occured on, e.g.
+//
+// error: Unknown attribute
+//  --> notes.md:3:4
+//   |
+// 3 | [](questoin)
+//   |    ^
+pub fn render(error: &ParseError, file_name: &str, source: &str) -> String {
+    let position = error.position();
+    let line = source.lines().nth(position.line).unwrap_or("");
+    let line_number = (position.line + 1).to_string();
+    let gutter = " ".repeat(line_number.len());
+    format!(
+        "error: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}^\n",
+        error.message(),
+        gutter, file_name, position,
+        gutter,
+        line_number, line,
+        gutter, " ".repeat(position.column),
+    )
+}
+
+// Print every error to stderr followed by a summary line
+pub fn report(errors: &[ParseError], file_name: &str, source: &str) {
+    for error in errors {
+        eprintln!("{}", render(error, file_name, source));
+    }
+    let plural = if errors.len() == 1 { "" } else { "s" };
+    eprintln!("error: could not parse `{}` due to {} error{}", file_name, errors.len(), plural);
+}
+
+// Print every warning to stderr, pointing at the card it is about when it came from a document
+pub fn report_warnings(warnings: &[Warning], cards: &[Card], documents: &[Document]) {
+    for warning in warnings {
+        eprintln!("warning: {}", warning.message);
+        point_at(&cards[warning.card], documents);
+    }
+}
+
+// Print a note for every card written on a single line, so prose that was split into a card by
+// mistake can be found
+pub fn report_inline(cards: &[Card], documents: &[Document]) {
+    for card in cards.iter().filter(|c| matches!(c.syntax, Some(Syntax::Inline { .. }))) {
+        eprintln!("note: inline card `{}`", card.front);
+        point_at(card, documents);
+    }
+}
+
+fn point_at(card: &Card, documents: &[Document]) {
+    let document = documents.iter().find(|d| Some(&d.path) == card.path.as_ref());
+    if let (Some(document), Some(span)) = (document, &card.span) {
+        eprintln!(" --> {}:{}", document.name(), to_position(&document.markdown, span.start));
+    }
+}
@@ -0,0 +1,325 @@
+//! The text editor for the fields of a card. The cursor moves over graphemes rather than bytes,
+//! so accented letters, CJK and emoji are edited as the single characters they look like
+use std::ops::Range;
+use tui::style::{Color, Style};
+use tui::widgets::Text;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// A field being edited, which may have several lines
+pub struct Editor {
+    pub text: String,
+    /// The byte offset of the cursor, which is always at the start of a grapheme
+    cursor: usize,
+    /// The column the cursor stays in when moving up and down through shorter lines
+    column: Option<usize>,
+}
+impl Editor {
+    // Start editing some text, with the cursor at the end
+    pub fn new(text: String) -> Editor {
+        Editor { cursor: text.len(), text, column: None }
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+        self.column = None;
+    }
+
+    // Remove the grapheme before the cursor
+    pub fn backspace(&mut self) {
+        let start = self.previous_boundary();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+        self.column = None;
+    }
+
+    // Remove the grapheme under the cursor
+    pub fn delete(&mut self) {
+        let end = self.next_boundary();
+        self.text.replace_range(self.cursor..end, "");
+        self.column = None;
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.previous_boundary();
+        self.column = None;
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = self.next_boundary();
+        self.column = None;
+    }
+
+    // Move to the start of the word before the cursor
+    pub fn word_left(&mut self) {
+        self.cursor = self.text[..self.cursor].split_word_bound_indices()
+            .rev()
+            .find(|(_, word)| is_word(word))
+            .map_or(0, |(i, _)| i);
+        self.column = None;
+    }
+
+    // Move to the end of the word after the cursor
+    pub fn word_right(&mut self) {
+        let cursor = self.cursor;
+        self.cursor = self.text[cursor..].split_word_bound_indices()
+            .find(|(_, word)| is_word(word))
+            .map_or(self.text.len(), |(i, word)| cursor + i + word.len());
+        self.column = None;
+    }
+
+    // Move to the start of the line
+    pub fn home(&mut self) {
+        self.cursor = self.line_start(self.cursor);
+        self.column = None;
+    }
+
+    // Move to the end of the line
+    pub fn end(&mut self) {
+        self.cursor = self.line_end(self.cursor);
+        self.column = None;
+    }
+
+    // Move to the line above, staying in the same column where it is long enough
+    pub fn up(&mut self) {
+        let start = self.line_start(self.cursor);
+        if start > 0 {
+            let column = self.column();
+            self.cursor = self.line_start(start - 1);
+            self.move_to_column(column);
+        }
+    }
+
+    // Move to the line below, staying in the same column where it is long enough
+    pub fn down(&mut self) {
+        let end = self.line_end(self.cursor);
+        if end < self.text.len() {
+            let column = self.column();
+            self.cursor = end + 1;
+            self.move_to_column(column);
+        }
+    }
+
+    // The lines of the text wrapped to the width of the pane, with the grapheme at the cursor
+    // highlighted. Only the lines that fit in the pane are returned, scrolling down to the cursor
+    pub fn render(&self, width: usize, height: usize) -> Vec<Text<'_>> {
+        let cursor_style = Style::default().fg(Color::White).bg(Color::Black);
+        let lines = wrap(&self.text, width);
+        let cursor_line = lines.iter().rposition(|line| line.start <= self.cursor).unwrap_or(0);
+        let first = (cursor_line + 1).saturating_sub(height.max(1));
+        let mut text = Vec::new();
+        for (i, line) in lines.into_iter().enumerate().skip(first).take(height.max(1)) {
+            if i > first {
+                text.push(Text::raw("\n"));
+            }
+            if i != cursor_line {
+                text.push(Text::raw(self.text[line].trim_end()));
+                continue;
+            }
+            let end = self.next_boundary();
+            let under_cursor = match &self.text[self.cursor..end] {
+                "" | "\n" => " ",
+                grapheme => grapheme,
+            };
+            text.push(Text::raw(&self.text[line.start..self.cursor]));
+            text.push(Text::styled(under_cursor, cursor_style));
+            text.push(Text::raw(&self.text[end.max(self.cursor)..line.end.max(end)]));
+        }
+        text
+    }
+
+    fn previous_boundary(&self) -> usize {
+        self.text[..self.cursor].grapheme_indices(true).next_back().map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..].graphemes(true).next().map_or(self.cursor, |g| self.cursor + g.len())
+    }
+
+    fn line_start(&self, offset: usize) -> usize {
+        self.text[..offset].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self, offset: usize) -> usize {
+        self.text[offset..].find('\n').map_or(self.text.len(), |i| offset + i)
+    }
+
+    // The column the cursor should be kept in, which is the width of the line before it
+    fn column(&mut self) -> usize {
+        let start = self.line_start(self.cursor);
+        let column = self.column.unwrap_or_else(|| self.text[start..self.cursor].width());
+        self.column = Some(column);
+        column
+    }
+
+    // Move along the line from its start to a column, or to its end if it is shorter
+    fn move_to_column(&mut self, column: usize) {
+        let start = self.cursor;
+        let end = self.line_end(start);
+        let mut width = 0;
+        for (i, grapheme) in self.text[start..end].grapheme_indices(true) {
+            width += grapheme.width();
+            if width > column {
+                self.cursor = start + i;
+                return;
+            }
+        }
+        self.cursor = end;
+    }
+}
+
+fn is_word(text: &str) -> bool {
+    text.chars().any(char::is_alphanumeric)
+}
+
+// Split text into the lines it is shown as in a pane of the given width, breaking long lines
+// after the last space that fits and breaking words that are too long on their own.
+// The ranges don't include the newlines, and keep the spaces at the end of wrapped lines
+pub fn wrap(text: &str, width: usize) -> Vec<Range<usize>> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for line in text.split('\n') {
+        let end = start + line.len();
+        let mut row = start;
+        let mut row_width = 0;
+        // Where the row can be broken, after a space, and the width up to there
+        let mut space: Option<(usize, usize)> = None;
+        for (i, grapheme) in line.grapheme_indices(true) {
+            let i = start + i;
+            let grapheme_width = grapheme.width();
+            let is_space = grapheme.chars().all(char::is_whitespace);
+            if row_width + grapheme_width > width && !is_space && i > row {
+                let (at, at_width) = space.filter(|&(at, _)| at > row).unwrap_or((i, row_width));
+                lines.push(row..at);
+                row = at;
+                row_width -= at_width;
+                space = None;
+            }
+            row_width += grapheme_width;
+            if is_space {
+                space = Some((i + grapheme.len(), row_width));
+            }
+        }
+        lines.push(row..end);
+        start = end + 1;
+    }
+    lines
+}
+
+// The text of a field as it is shown in a pane, wrapped the same way as it is while editing
+pub fn wrapped(text: &str, width: usize) -> String {
+    wrap(text, width).into_iter()
+        .map(|line| text[line].trim_end())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The text shown by the editor, with the grapheme at the cursor in brackets
+    fn shown(editor: &Editor, width: usize, height: usize) -> String {
+        editor.render(width, height).iter()
+            .map(|text| match text {
+                Text::Raw(text) => text.to_string(),
+                Text::Styled(text, _) => format!("[{}]", text),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edits_whole_graphemes() {
+        // An `e` followed by a combining accent, then a precomposed `é` and an emoji with a skin tone
+        let mut editor = Editor::new("ae\u{301}é👍🏽".to_string());
+        editor.backspace();
+        assert_eq!(editor.text, "ae\u{301}é");
+        editor.left();
+        editor.left();
+        assert_eq!(editor.cursor, 1);
+        editor.backspace();
+        assert_eq!(editor.text, "e\u{301}é");
+        editor.right();
+        editor.insert('日');
+        assert_eq!(editor.text, "e\u{301}日é");
+        editor.delete();
+        assert_eq!(editor.text, "e\u{301}日");
+        editor.left();
+        editor.left();
+        editor.delete();
+        assert_eq!(editor.text, "日");
+        // Nothing happens past either end
+        editor.left();
+        editor.backspace();
+        editor.end();
+        editor.right();
+        editor.delete();
+        assert_eq!(editor.text, "日");
+        assert_eq!(editor.cursor, "日".len());
+    }
+
+    #[test]
+    fn words() {
+        let mut editor = Editor::new("naïve café, ok".to_string());
+        editor.word_left();
+        assert_eq!(&editor.text[editor.cursor..], "ok");
+        editor.word_left();
+        assert_eq!(&editor.text[editor.cursor..], "café, ok");
+        editor.word_right();
+        assert_eq!(&editor.text[editor.cursor..], ", ok");
+    }
+
+    #[test]
+    fn up_and_down_keep_the_column() {
+        let mut editor = Editor::new("日本語\nab\nabcdef".to_string());
+        editor.up();
+        editor.up();
+        editor.home();
+        editor.right();
+        // After `日`, which is two columns wide
+        editor.down();
+        assert_eq!(&editor.text[editor.cursor..], "\nabcdef");
+        editor.down();
+        assert_eq!(&editor.text[editor.cursor..], "cdef");
+        editor.up();
+        editor.up();
+        assert_eq!(&editor.text[editor.cursor..], "本語\nab\nabcdef");
+        // Half way through a wide character goes to its start
+        editor.down();
+        editor.home();
+        editor.right();
+        editor.up();
+        assert_eq!(&editor.text[editor.cursor..], "日本語\nab\nabcdef");
+    }
+
+    #[test]
+    fn wraps_at_spaces_and_widths() {
+        let text = "hello world foo";
+        let lines: Vec<&str> = wrap(text, 6).into_iter().map(|line| &text[line]).collect();
+        assert_eq!(lines, ["hello ", "world ", "foo"]);
+        assert_eq!(wrapped("abcdefgh", 3), "abc\ndef\ngh");
+        assert_eq!(wrapped("日本語", 4), "日本\n語");
+        assert_eq!(wrapped("e\u{301}e\u{301}e\u{301}", 2), "e\u{301}e\u{301}\ne\u{301}");
+        assert_eq!(wrapped("one\n\ntwo", 10), "one\n\ntwo");
+    }
+
+    #[test]
+    fn renders_the_cursor_and_scrolls_to_it() {
+        let mut editor = Editor::new("1\n2\n3\n4".to_string());
+        assert_eq!(shown(&editor, 10, 2), "3\n4[ ]");
+        editor.up();
+        editor.up();
+        editor.home();
+        // The cursor's line is the last one shown
+        assert_eq!(shown(&editor, 10, 2), "1\n[2]");
+        // The cursor row is found among wrapped lines too
+        let mut editor = Editor::new("hello world 日本".to_string());
+        assert_eq!(shown(&editor, 6, 1), "日本[ ]");
+        editor.cursor = "hello ".len();
+        assert_eq!(shown(&editor, 6, 1), "[w]orld ");
+        editor.home();
+        assert_eq!(shown(&editor, 6, 3), "[h]ello \nworld\n日本");
+    }
+}
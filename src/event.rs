@@ -1,4 +1,5 @@
 /// Copied from tui-rs/examples/util/event
+use std::cell::Cell;
 use std::io;
 use std::sync::mpsc;
 use std::thread;
@@ -16,6 +17,10 @@ pub enum Event<I> {
 /// type is handled in its own thread and returned to a common `Receiver`
 pub struct Events {
     rx: mpsc::Receiver<Event<Key>>,
+    /// Asks the input thread to read the next key. It only reads when asked, so that another
+    /// program, like an editor, can be given the terminal between keys
+    request_tx: mpsc::Sender<()>,
+    requested: Cell<bool>,
     #[allow(dead_code)]
     input_handle: thread::JoinHandle<()>,
     #[allow(dead_code)]
@@ -44,18 +49,22 @@ impl Events {
 
     pub fn with_config(config: Config) -> Events {
         let (tx, rx) = mpsc::channel();
+        let (request_tx, request_rx) = mpsc::channel();
         let input_handle = {
             let tx = tx.clone();
             thread::spawn(move || {
                 let stdin = io::stdin();
-                for evt in stdin.keys() {
-                    if let Ok(key) = evt {
-                        if tx.send(Event::Input(key)).is_err() {
-                            return;
-                        }
-                        if key == config.exit_key {
-                            return;
-                        }
+                let mut keys = stdin.keys();
+                for () in request_rx {
+                    let key = match keys.by_ref().flatten().next() {
+                        Some(key) => key,
+                        None => return,
+                    };
+                    if tx.send(Event::Input(key)).is_err() {
+                        return;
+                    }
+                    if key == config.exit_key {
+                        return;
                     }
                 }
             })
@@ -72,12 +81,22 @@ impl Events {
         };
         Events {
             rx,
+            request_tx,
+            requested: Cell::new(false),
             input_handle,
             tick_handle,
         }
     }
 
     pub fn next(&self) -> Result<Event<Key>, mpsc::RecvError> {
-        self.rx.recv()
+        if !self.requested.replace(true) {
+            // This only fails once the input thread has stopped at the exit key
+            let _ = self.request_tx.send(());
+        }
+        let event = self.rx.recv()?;
+        if let Event::Input(_) = event {
+            self.requested.set(false);
+        }
+        Ok(event)
     }
 }
@@ -0,0 +1,109 @@
+use crate::apkg;
+use crate::card::{Card, GuidSource};
+use crate::media;
+use crate::render;
+use crate::text::{self, Delimiter};
+
+use std::{env, fs, io::{self, prelude::*}, path::{Path, PathBuf}, process};
+
+/// The different kinds of file the cards can be exported to
+#[derive(PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    /// A delimited text file that is imported through Anki's import dialog
+    Text,
+    /// An Anki package that can be opened directly
+    Apkg,
+}
+impl Format {
+    // Choose the format from the extension of the output name, defaulting to text
+    pub fn from_name(name: &str) -> Format {
+        if name.ends_with(".apkg") {
+            Format::Apkg
+        } else {
+            Format::Text
+        }
+    }
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Text => "txt",
+            Format::Apkg => "apkg",
+        }
+    }
+}
+
+/// Settings that change how cards are exported
+pub struct Options {
+    pub format: Format,
+    /// What separates the fields of text files
+    pub delimiter: Delimiter,
+    /// The deck the cards are put in. Defaults to the name of the output file
+    pub deck: Option<String>,
+    /// Export the fields exactly as written instead of converting their markdown to HTML
+    pub raw: bool,
+    /// What the GUIDs of cards without an id marker are made from
+    pub guid_source: GuidSource,
+    /// Where to copy the media of text exports, usually the `collection.media` folder of an
+    /// Anki profile. Packages bundle their media instead
+    pub media_dir: Option<PathBuf>,
+}
+
+// Export cards to the file `name`, or to stdout if the name is `-`
+pub fn export(cards: &[Card], name: &str, options: &Options) -> Result<(), failure::Error> {
+    let mut cards = cards.to_vec();
+    // Work out every GUID from the markdown so it doesn't change with the rendering, and so
+    // the exporters can use the ids directly
+    for card in cards.iter_mut() {
+        card.id = Some(card.guid(options.guid_source));
+    }
+    let media = media::collect(&mut cards, options.raw);
+    for card in cards.iter_mut() {
+        if options.raw {
+            card.front = render::to_mathjax(&card.front);
+            card.back = render::to_mathjax(&card.back);
+        } else {
+            card.front = render::to_html(&card.front);
+            card.back = render::to_html(&card.back);
+        }
+    }
+    let deck = match &options.deck {
+        Some(deck) => deck.as_str(),
+        None => Path::new(name).file_stem().and_then(|s| s.to_str())
+            .filter(|_| name != "-")
+            .unwrap_or("Default"),
+    };
+    // Give the exporters the full name of every subdeck
+    for card in cards.iter_mut() {
+        card.deck = card.deck.as_ref().map(|subdeck| format!("{}::{}", deck, subdeck));
+    }
+
+    match options.format {
+        Format::Text => {
+            let text = text::write(&cards, options.delimiter, deck, options.deck.is_some());
+            if name == "-" {
+                io::stdout().write_all(text.as_bytes())?;
+            } else {
+                fs::write(name, text)?;
+            }
+            match &options.media_dir {
+                Some(directory) => media::copy(&media, directory)?,
+                None if !media.is_empty() => eprintln!(
+                    "note: the cards refer to {} media files, use --media-dir to copy them into \
+                    Anki's collection.media folder", media.len()),
+                None => {},
+            }
+        },
+        Format::Apkg => {
+            if name == "-" {
+                // Packages are zip files which can't be streamed, so build it in a temporary file
+                let path = env::temp_dir().join(format!("md-to-anki-{}.apkg", process::id()));
+                let result = apkg::write(&cards, &media, deck, &path)
+                    .and_then(|_| Ok(io::stdout().write_all(&fs::read(&path)?)?));
+                let _ = fs::remove_file(&path);
+                result?
+            } else {
+                apkg::write(&cards, &media, deck, Path::new(name))?
+            }
+        },
+    }
+    Ok(())
+}
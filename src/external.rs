@@ -0,0 +1,113 @@
+//! Editing a card in `$EDITOR`. The card is written to a temporary markdown file with a heading
+//! for each field, which is read back once the editor exits
+use crate::card::{self, Card};
+use std::{env, fs, path::Path, process::{self, Command}};
+
+// The headings the fields are under in the file
+const HEADINGS: [&str; 3] = ["## Front", "## Back", "## Tags"];
+
+/// The fields of a card as they were edited
+#[derive(PartialEq)]
+pub struct Fields {
+    pub front: String,
+    pub back: String,
+    pub tags: Vec<String>,
+}
+
+// Open the card in `$EDITOR` and wait for it to exit, returning the fields if they were changed
+pub fn edit(card: &Card) -> Result<Option<Fields>, failure::Error> {
+    let path = env::temp_dir().join(format!("md-to-anki-{}.md", process::id()));
+    let text = to_text(card);
+    fs::write(&path, &text).map_err(|e| failure::format_err!("couldn't write `{}`: {}", path.display(), e))?;
+    let edited = run_editor(&path).and_then(|()| {
+        let edited = fs::read_to_string(&path)
+            .map_err(|e| failure::format_err!("couldn't read `{}`: {}", path.display(), e))?;
+        from_text(&edited)
+    });
+    let _ = fs::remove_file(&path);
+    // The card is read back from its own file too, so only real changes count
+    let edited = edited?;
+    Ok(if edited == from_text(&text)? { None } else { Some(edited) })
+}
+
+fn run_editor(path: &Path) -> Result<(), failure::Error> {
+    let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    // The editor can be given arguments, like `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or_else(|| failure::format_err!("`$EDITOR` is empty"))?;
+    let status = Command::new(program).args(words).arg(path).status()
+        .map_err(|e| failure::format_err!("couldn't run `{}`: {}", editor, e))?;
+    if !status.success() {
+        failure::bail!("`{}` exited with {}", editor, status);
+    }
+    Ok(())
+}
+
+// Write the fields under their headings, with each `<br>` separated line on a line of its own
+fn to_text(card: &Card) -> String {
+    let fields = [card::field_text(&card.front), card::field_text(&card.back), card.tag_text()];
+    let mut text = "<!-- Edit the card, then save and quit. Each line under Front and Back is a line of the field -->\n".to_string();
+    for (heading, field) in HEADINGS.iter().zip(&fields) {
+        text += &format!("\n{}\n{}\n", heading, field);
+    }
+    text
+}
+
+// Read the fields back from under their headings. Anything before the first heading is ignored
+fn from_text(text: &str) -> Result<Fields, failure::Error> {
+    let mut fields: [Option<String>; 3] = Default::default();
+    let mut current = None;
+    for line in text.lines() {
+        match HEADINGS.iter().position(|heading| line.trim_end() == *heading) {
+            Some(i) => {
+                fields[i].get_or_insert_with(String::new);
+                current = Some(i);
+            },
+            None => if let Some(field) = current.and_then(|i| fields[i].as_mut()) {
+                field.push_str(line);
+                field.push('\n');
+            },
+        }
+    }
+    let [front, back, tags] = fields;
+    let missing = |i: usize| failure::format_err!("the card has no `{}` heading", HEADINGS[i]);
+    Ok(Fields {
+        front: card::text_field(&front.ok_or_else(|| missing(0))?, false),
+        back: card::text_field(&back.ok_or_else(|| missing(1))?, true),
+        tags: tags.ok_or_else(|| missing(2))?.split_whitespace().map(String::from).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_survive_a_round_trip() {
+        let mut card = Card::new("Two<br>lines?".to_string(), "First<br>Second<br>".to_string(), vec!["Bio::Cells".to_string(), "exam".to_string()]);
+        card.add_code_to_back("rust", "fn main() {\n\n}\n");
+        let fields = from_text(&to_text(&card)).unwrap();
+        assert_eq!(fields.front, card.front);
+        assert_eq!(fields.back, card.back);
+        assert_eq!(fields.tags, card.tags);
+    }
+
+    #[test]
+    fn reads_edited_text() {
+        let text = "Notes before the first heading\n## Front\n\nQuestion\n\n## Back  \nOne\n\n\nTwo\n## Tags\n\n  a   b::c\n\n";
+        let fields = from_text(text).unwrap();
+        assert_eq!(fields.front, "Question");
+        // Blank lines between lines of a field don't make empty lines
+        assert_eq!(fields.back, "One<br>Two<br>");
+        assert_eq!(fields.tags, ["a", "b::c"]);
+        // Empty fields are fine
+        let fields = from_text("## Front\n## Back\n## Tags\n").unwrap();
+        assert!(fields.front.is_empty() && fields.back.is_empty() && fields.tags.is_empty());
+    }
+
+    #[test]
+    fn headings_are_needed() {
+        let error = from_text("## Front\nQ\n## Tags\n").err().unwrap();
+        assert_eq!(error.to_string(), "the card has no `## Back` heading");
+    }
+}
@@ -8,32 +8,228 @@ mod event;
 use crate::event::{Event, Events};
 
 mod app;
-use crate::app::App;
+use crate::app::{App, Prompt};
+
+mod editor;
+
+mod external;
+
+mod search;
 
 mod ui;
 use crate::ui::Ui;
 
-use std::{env, process, fs, io::{Error, prelude::*}};
+mod apkg;
+
+mod text;
+
+mod export;
+use crate::export::export;
+
+mod diagnostic;
+
+mod render;
+
+mod media;
+
+mod check;
+
+mod cli;
+use crate::cli::{Cli, Command, ExportArgs, InputArgs, UsageError};
+
+mod source;
+use crate::source::Document;
+
+mod schedule;
+use crate::schedule::{Answer, Schedule};
+
+mod study;
+use crate::study::Study;
+
+use clap::Parser;
+use std::process;
 use termion::event::Key;
 
-fn main() -> Result<(), failure::Error>{
+// Exit codes
+const EXIT_OK: i32 = 0;
+/// The documents have errors, or warnings when linting strictly
+const EXIT_INVALID: i32 = 1;
+/// The arguments are wrong. Clap exits with this too
+const EXIT_USAGE: i32 = 2;
+/// A file couldn't be read or written
+const EXIT_IO: i32 = 3;
 
-    // Setup event handlers
-    let events = Events::new();
+fn main() {
+    let cli = Cli::parse();
+    let code = match run(cli.command) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            if e.downcast_ref::<UsageError>().is_some() { EXIT_USAGE } else { EXIT_IO }
+        }
+    };
+    process::exit(code);
+}
 
-    // Parsing file
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        println!("Program requires two arguments: <input_file_location> <output_file_name>");
-        process::exit(1);
+fn run(command: Command) -> Result<i32, failure::Error> {
+    match command {
+        Command::Edit(args) => {
+            if args.output.as_deref() == Some("-") {
+                // The interface is drawn on stdout
+                return Err(UsageError("`edit` can't export to stdout, so give a file with --output".to_string()).into());
+            }
+            let (documents, cards) = match load(&args.input, args.write_ids)? {
+                Some(loaded) => loaded,
+                None => return Ok(EXIT_INVALID),
+            };
+            let editing = Editing { documents, options: parse_options(&args.input) };
+            edit(cards, editing, &output_name(&args)?, &export_options(&args))
+        },
+        Command::Convert(args) => {
+            let (documents, cards) = match load(&args.input, args.write_ids)? {
+                Some(loaded) => loaded,
+                None => return Ok(EXIT_INVALID),
+            };
+            convert(&cards, &documents, &output_name(&args)?, &export_options(&args))
+        },
+        Command::Lint { input, strict } => {
+            let (documents, cards) = match load(&input, false)? {
+                Some(loaded) => loaded,
+                None => return Ok(EXIT_INVALID),
+            };
+            let warnings = check::check(&cards);
+            diagnostic::report_inline(&cards, &documents);
+            diagnostic::report_warnings(&warnings, &cards, &documents);
+            let inline = cards.iter().filter(|c| matches!(c.syntax, Some(Syntax::Inline { .. }))).count();
+            eprintln!("{} cards ({} inline), {} warnings", cards.len(), inline, warnings.len());
+            Ok(if strict && !warnings.is_empty() { EXIT_INVALID } else { EXIT_OK })
+        },
+        Command::Study { input, schedule, all } => {
+            let (_, cards) = match load(&input, false)? {
+                Some(loaded) => loaded,
+                None => return Ok(EXIT_INVALID),
+            };
+            let path = match schedule.or_else(|| source::default_path(&input.inputs[0], "study.json")) {
+                Some(path) => path,
+                None => return Err(UsageError(format!(
+                    "can't name the schedule after `{}`, so give one with --schedule", input.inputs[0]
+                )).into()),
+            };
+            let study = Study::new(cards, Schedule::load(&path)?, input.guid_from, all);
+            run_study(study)
+        },
+        Command::Stats { input } => {
+            let (_, cards) = match load(&input, false)? {
+                Some(loaded) => loaded,
+                None => return Ok(EXIT_INVALID),
+            };
+            stats(&cards);
+            Ok(EXIT_OK)
+        },
     }
-    let markdown = fs::read_to_string(&args[1])
-        .expect("Something went wrong reading the file");
-    let cards = match parse_md(&markdown) {
-        Ok(parsed_md) => { parsed_md },
-        Err(e) => { println!("Error: {}",e); return Ok(()) }
-    };
+}
+
+// The documents that were read and all the cards parsed from them
+type Loaded = (Vec<Document>, Vec<Card>);
+
+// Read and parse every input, reporting any errors, and write ids into the documents if asked
+// to. Returns `None` if there were parse errors
+fn load(args: &InputArgs, write_ids: bool) -> Result<Option<Loaded>, failure::Error> {
+    let mut documents = Vec::new();
+    let mut cards = Vec::new();
+    let mut failed = false;
+    let mut paths = Vec::new();
+    for input in &args.inputs {
+        paths.append(&mut source::find_documents(input)?);
+    }
+    let options = parse_options(args);
+    for (path, relative) in paths {
+        let mut document = Document::load(&path, &relative)
+            .map_err(|e| failure::format_err!("couldn't read `{}`: {}", path.display(), e))?;
+        match document.parse(&options) {
+            Ok(mut parsed) => {
+                if write_ids {
+                    parsed = document.write_ids(&parsed, args.guid_from, &options)?;
+                }
+                let parts = document.path_parts();
+                for card in parsed.iter_mut() {
+                    if args.path_tags {
+                        for part in &parts {
+                            card.add_tag(&part.replace(" ", "_"));
+                        }
+                    }
+                    if args.path_decks {
+                        // Decks from headings go inside the deck for the file
+                        card.deck = Some(parts.iter().cloned()
+                            .chain(card.deck.take())
+                            .collect::<Vec<_>>()
+                            .join("::"));
+                    }
+                }
+                cards.append(&mut parsed);
+            },
+            Err(errors) => {
+                diagnostic::report(&errors, &document.name(), &document.markdown);
+                failed = true;
+            },
+        }
+        documents.push(document);
+    }
+    if failed {
+        Ok(None)
+    } else {
+        Ok(Some((documents, cards)))
+    }
+}
+
+fn parse_options(args: &InputArgs) -> parse::Options {
+    parse::Options {
+        heading_decks: args.heading_decks,
+        tag_style: args.tags,
+        inline_separator: args.inline_separator.clone(),
+        reversed_separator: args.reversed_separator.clone(),
+    }
+}
+
+// The file to export to, which defaults to the first input with the extension of the format
+fn output_name(args: &ExportArgs) -> Result<String, failure::Error> {
+    match &args.output {
+        Some(output) => Ok(output.clone()),
+        None => {
+            let input = &args.input.inputs[0];
+            let format = args.format.unwrap_or(export::Format::Text);
+            match source::default_path(input, format.extension()) {
+                Some(path) => Ok(path.display().to_string()),
+                None => Err(UsageError(format!("can't name the output after `{}`, so give one with --output", input)).into()),
+            }
+        },
+    }
+}
+
+fn export_options(args: &ExportArgs) -> export::Options {
+    export::Options {
+        format: args.format.unwrap_or_else(|| {
+            export::Format::from_name(args.output.as_deref().unwrap_or(""))
+        }),
+        delimiter: args.delimiter,
+        deck: args.deck.clone(),
+        raw: args.raw,
+        guid_source: args.input.guid_from,
+        media_dir: args.media_dir.clone(),
+    }
+}
 
+/// The documents being edited, and how to parse them again after saving
+struct Editing {
+    documents: Vec<Document>,
+    options: parse::Options,
+}
+
+// Open the interface to edit the cards, saving them to the markdown or exporting them when asked to
+fn edit(cards: Vec<Card>, mut editing: Editing, output: &str, options: &export::Options)
+    -> Result<i32, failure::Error> {
+    // Setup event handlers
+    let events = Events::new();
     let mut app = App::new(cards);
     let mut ui = Ui::new()?;
 
@@ -47,16 +243,139 @@ fn main() -> Result<(), failure::Error>{
                     app = app.disable_edit();
                 }
                 Key::Char(char) => {
-                    app.push(char);
+                    app.editor.insert(char);
+                }
+                Key::Backspace => {
+                    app.editor.backspace();
+                }
+                Key::Delete => {
+                    app.editor.delete();
+                }
+                Key::Left => {
+                    app.editor.left();
+                }
+                Key::Right => {
+                    app.editor.right();
+                }
+                Key::Up => {
+                    app.editor.up();
+                }
+                Key::Down => {
+                    app.editor.down();
+                }
+                Key::Home | Key::Ctrl('a') => {
+                    app.editor.home();
+                }
+                Key::End | Key::Ctrl('e') => {
+                    app.editor.end();
+                }
+                Key::Alt('b') => {
+                    app.editor.word_left();
+                }
+                Key::Alt('f') => {
+                    app.editor.word_right();
+                }
+                _ => {}
+            }}
+        } else if app.prompt.is_some() {
+            if let Event::Input(input) = events.next()? { match input {
+                Key::Esc => {
+                    app.cancel_prompt();
+                }
+                Key::Char('\n') => {
+                    app.answer_prompt();
+                }
+                Key::Char(char) => {
+                    app.answer.insert(char);
+                }
+                Key::Backspace => {
+                    app.answer.backspace();
+                }
+                Key::Delete => {
+                    app.answer.delete();
+                }
+                Key::Left => {
+                    app.answer.left();
+                }
+                Key::Right => {
+                    app.answer.right();
+                }
+                Key::Home | Key::Ctrl('a') => {
+                    app.answer.home();
+                }
+                Key::End | Key::Ctrl('e') => {
+                    app.answer.end();
+                }
+                _ => {}
+            }}
+        } else if app.tag_panel {
+            if let Event::Input(input) = events.next()? { match input {
+                Key::Esc | Key::Char('T') => {
+                    app.toggle_tag_panel();
+                }
+                Key::Down => {
+                    app.move_tag(true);
+                }
+                Key::Up => {
+                    app.move_tag(false);
+                }
+                Key::Char('\n') => {
+                    app.filter_by_tag();
+                }
+                Key::Char('r') => {
+                    app.start_tag_prompt(false);
+                }
+                Key::Char('m') => {
+                    app.start_tag_prompt(true);
+                }
+                Key::Char('a') => {
+                    app.start_prompt(Prompt::Tag);
+                }
+                Key::Char('d') => {
+                    app.remove_tag();
+                }
+                Key::Char('u') => {
+                    app.undo();
+                }
+                Key::Ctrl('r') => {
+                    app.redo();
+                }
+                _ => {}
+            }}
+        } else if app.is_searching {
+            if let Event::Input(input) = events.next()? { match input {
+                Key::Esc => {
+                    app.clear_search();
+                }
+                Key::Char('\n') => {
+                    app.finish_search();
+                }
+                Key::Ctrl('f') => {
+                    app.toggle_filter();
+                }
+                Key::Char(char) => {
+                    app.search.insert(char);
+                    app.update_search();
                 }
                 Key::Backspace => {
-                    app.pop();
+                    app.search.backspace();
+                    app.update_search();
+                }
+                Key::Delete => {
+                    app.search.delete();
+                    app.update_search();
                 }
                 Key::Left => {
-                    app.move_cursor_left();
+                    app.search.left();
                 }
                 Key::Right => {
-                    app.move_cursor_right();
+                    app.search.right();
+                }
+                Key::Home | Key::Ctrl('a') => {
+                    app.search.home();
+                }
+                Key::End | Key::Ctrl('e') => {
+                    app.search.end();
                 }
                 _ => {}
             }}
@@ -64,6 +383,45 @@ fn main() -> Result<(), failure::Error>{
                 Key::Char('q') => {
                     break;
                 }
+                Key::Char('/') => {
+                    app.start_search();
+                }
+                Key::Char('n') if !app.query.is_empty() => {
+                    app.find_match(true, false);
+                }
+                Key::Char('N') if !app.query.is_empty() => {
+                    app.find_match(false, false);
+                }
+                Key::Esc if app.range_from.is_some() => {
+                    app.cancel_range();
+                }
+                Key::Esc if !app.query.is_empty() => {
+                    app.clear_search();
+                }
+                Key::Esc => {
+                    app.clear_marks();
+                }
+                Key::Ctrl('f') => {
+                    app.toggle_filter();
+                }
+                Key::Char(' ') => {
+                    app.toggle_mark();
+                }
+                Key::Char('v') => {
+                    app.mark_range();
+                }
+                Key::Char('*') => {
+                    app.mark_matching();
+                }
+                Key::Char('T') => {
+                    app.toggle_tag_panel();
+                }
+                Key::Char('D') => {
+                    app.start_prompt(Prompt::Deck);
+                }
+                Key::Char('z') => {
+                    app.toggle_suspended();
+                }
                 Key::Left => {
                     app.selected = None;
                 }
@@ -76,45 +434,168 @@ fn main() -> Result<(), failure::Error>{
                 Key::Char('f') => {
                     app.enable_edit(Attribute::Front);
                 }
+                Key::Char('e') if !app.cards.is_empty() => {
+                    let card = &app.cards[app.last_selected];
+                    let (suspended, edited) = ui.suspend(|| external::edit(card))?;
+                    ui = suspended;
+                    match edited {
+                        Ok(Some(fields)) => app.replace_fields(fields),
+                        Ok(None) => {},
+                        Err(error) => app.text = format!("error: {}", error),
+                    }
+                }
                 Key::Char('b') => {
                     app.enable_edit(Attribute::Back);
                 }
+                Key::Char('t') if app.cards.iter().any(|card| card.marked) => {
+                    app.start_prompt(Prompt::Retag);
+                }
                 Key::Char('t') => {
                     app.enable_edit(Attribute::MainTag);
                 }
                 Key::Char('d') => {
-                    app.cards.remove(app.last_selected);
-                    if app.last_selected >= app.cards.len() {
-                        app.selected = Some(0);
-                        app.last_selected = 0;
-                    }
+                    app.delete_card();
                 }
                 Key::Char('n') => {
-                    app.cards.push(Card::new("New Card".to_string(), "".to_string(), "".to_string()));
+                    app.new_card();
+                }
+                Key::Char('J') => {
+                    app.move_card(true);
+                }
+                Key::Char('K') => {
+                    app.move_card(false);
+                }
+                Key::Char('u') => {
+                    app.undo();
+                }
+                Key::Ctrl('r') => {
+                    app.redo();
+                }
+                Key::Char('s') => {
+                    app.text = match save(&mut app, &mut editing) {
+                        Ok(0) => "Nothing to save".to_string(),
+                        Ok(saved) => format!("Saved {} files", saved),
+                        Err(e) => format!("error: {}", e),
+                    };
                 }
                 Key::Char('x') => {
-                    export(&app.cards, args[2].clone())?;
-                    break;
+                    let cards = app.cards.clone();
+                    let (suspended, exported) = export_from(ui, &mut app, &cards, output, options)?;
+                    ui = suspended;
+                    if exported {
+                        break;
+                    }
+                }
+                Key::Char('X') if app.cards.iter().any(|card| card.marked) => {
+                    let marked: Vec<Card> = app.cards.iter().filter(|card| card.marked).cloned().collect();
+                    let (suspended, exported) = export_from(ui, &mut app, &marked, output, options)?;
+                    ui = suspended;
+                    if exported {
+                        break;
+                    }
+                }
+                Key::Char('X') => {
+                    app.text = "No cards are marked to export".to_string();
                 }
                 _ => {}
         }}
 
     }
-    Ok(())
+    Ok(EXIT_OK)
+}
+
+// Export the cards with the interface out of the way, so that the output and anything printed
+// about it end up on the terminal. If the export fails the error is shown and editing carries on,
+// so no edits are lost. Returns whether the cards were exported
+fn export_from(ui: Ui, app: &mut App, cards: &[Card], output: &str, options: &export::Options)
+    -> Result<(Ui, bool), failure::Error> {
+    let (ui, exported) = ui.suspend(|| export(cards, output, options))?;
+    if let Err(e) = &exported {
+        app.text = format!("error: {}", e);
+    }
+    Ok((ui, exported.is_ok()))
+}
+
+// Ask each card in the study session in turn, then print how many were studied
+fn run_study(mut study: Study) -> Result<i32, failure::Error> {
+    let total = study.remaining();
+    {
+        let events = Events::new();
+        let mut ui = Ui::new()?;
+        while study.remaining() > 0 {
+            ui.render_study(&study)?;
+            if let Event::Input(input) = events.next()? { match input {
+                Key::Char('q') => {
+                    break;
+                }
+                Key::Char(' ') | Key::Char('\n') if !study.revealed => {
+                    study.reveal();
+                }
+                Key::Char('1') if study.revealed => {
+                    study.respond(Answer::Again)?;
+                }
+                Key::Char('2') if study.revealed => {
+                    study.respond(Answer::Hard)?;
+                }
+                Key::Char('3') | Key::Char(' ') | Key::Char('\n') if study.revealed => {
+                    study.respond(Answer::Good)?;
+                }
+                Key::Char('4') if study.revealed => {
+                    study.respond(Answer::Easy)?;
+                }
+                _ => {}
+            }}
+        }
+    }
+    eprintln!("Studied {} of {} cards due, with {} answers", total - study.remaining(), total, study.reviewed);
+    Ok(EXIT_OK)
+}
+
+// Write the edited, new and deleted cards back into their documents, returning how many
+// documents were changed. Cards that weren't read from a document aren't saved
+fn save(app: &mut App, editing: &mut Editing) -> Result<usize, failure::Error> {
+    let mut saved = 0;
+    let mut result = Ok(());
+    for document in editing.documents.iter_mut() {
+        match document.save(&mut app.cards, &mut app.deleted, &editing.options) {
+            Ok(changed) => saved += changed as usize,
+            Err(e) => {
+                result = Err(e);
+                break;
+            },
+        }
+    }
+    // The history refers to the markdown as it was, so it can't be undone past a save
+    if saved > 0 {
+        app.clear_history();
+    }
+    result.map(|_| saved)
+}
+
+// Export the cards straight away without starting the interface, printing a summary to stderr
+fn convert(cards: &[Card], documents: &[Document], output: &str, options: &export::Options)
+    -> Result<i32, failure::Error> {
+    let warnings = check::check(cards);
+    diagnostic::report_warnings(&warnings, cards, documents);
+    export(cards, output, options)?;
+
+    for (tag, count) in count_tags(cards) {
+        eprintln!("{:>6} {}", count, tag);
+    }
+    eprintln!("Exported {} cards to `{}` with {} warnings", cards.len(), output, warnings.len());
+    Ok(EXIT_OK)
 }
 
-// Export cards by creating a text file Anki can import
-fn export(cards: &[Card], name: String) -> Result<(), Error> {
-    let mut output = "".to_string();
-    for card in cards {
-        output += &card.export();
+// Print the number of cards for each tag and note type
+fn stats(cards: &[Card]) {
+    println!("Tags:");
+    for (tag, count) in count_tags(cards) {
+        println!("{:>6} {}", count, tag);
     }
-    let mut output_file = fs::OpenOptions::new()
-    .write(true)
-    .create(true)
-    .open(name + ".txt")?;
-    if let Err(e) = writeln!(output_file, "{}", output) {
-        eprintln!("Couldn't write to file: {}", e);
+    println!("Note types:");
+    for note_type in &[NoteType::Basic, NoteType::BasicAndReversed, NoteType::Cloze] {
+        let count = cards.iter().filter(|c| c.note_type == *note_type).count();
+        println!("{:>6} {}", count, note_type.name());
     }
-    Ok(())
+    println!("{:>6} cards in total", cards.len());
 }
@@ -0,0 +1,226 @@
+//! Finding the images and audio that cards refer to, so they can be copied into Anki's media
+//! folder or bundled into a package. Anki keeps all media in one flat folder, so references
+//! are rewritten to the name the file is given there
+use crate::card::Card;
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+const IMAGE_EXTENSIONS: [&str; 10] = ["png", "jpg", "jpeg", "gif", "svg", "webp", "bmp", "tif", "tiff", "avif"];
+// Anki plays video with the same `[sound:...]` syntax as audio
+const SOUND_EXTENSIONS: [&str; 9] = ["mp3", "ogg", "oga", "wav", "m4a", "flac", "opus", "mp4", "webm"];
+
+/// A file that has to be put in Anki's media folder
+pub struct Media {
+    /// The name of the file in the media folder
+    pub name: String,
+    /// Where the file is now
+    pub path: PathBuf,
+}
+
+/// The kinds of media Anki can show on a card
+#[derive(PartialEq, Clone, Copy)]
+enum Kind {
+    Image,
+    Sound,
+}
+
+/// A reference to a local media file in a field
+struct Reference {
+    /// The markdown of the image or link
+    range: std::ops::Range<usize>,
+    kind: Kind,
+    path: PathBuf,
+    /// The text of an image, which is kept as its alt text
+    alt: String,
+}
+
+// Rewrite the media references in every card to their names in the media folder, and return
+// the files they refer to. Images become `![alt](name)`, or `<img src="name">` if the fields are
+// exported raw, and audio becomes `[sound:name]`. Files that don't exist are left out, since
+// they are reported by `lint`
+pub fn collect(cards: &mut [Card], raw: bool) -> Vec<Media> {
+    let mut media: Vec<Media> = Vec::new();
+    let mut names: HashMap<PathBuf, String> = HashMap::new();
+    for card in cards.iter_mut() {
+        let base = base_directory(card);
+        for field in [&mut card.front, &mut card.back] {
+            let mut rewritten = String::new();
+            let mut last = 0;
+            for reference in references(field, &base) {
+                let name = match names.get(&canonical(&reference.path)) {
+                    Some(name) => name.clone(),
+                    None => {
+                        let name = media_name(&reference.path, &media);
+                        names.insert(canonical(&reference.path), name.clone());
+                        if reference.path.is_file() {
+                            media.push(Media { name: name.clone(), path: reference.path.clone() });
+                        }
+                        name
+                    },
+                };
+                rewritten += &field[last..reference.range.start];
+                rewritten += &match (reference.kind, raw) {
+                    (Kind::Sound, _) => format!("[sound:{}]", name),
+                    (Kind::Image, true) => format!("<img src=\"{}\">", name.replace('"', "&quot;")),
+                    (Kind::Image, false) => {
+                        let alt = reference.alt.replace('[', "\\[").replace(']', "\\]");
+                        format!("![{}](<{}>)", alt, name)
+                    },
+                };
+                last = reference.range.end;
+            }
+            rewritten += &field[last..];
+            *field = rewritten;
+        }
+    }
+    media
+}
+
+// Find the media files referred to by a card that don't exist
+pub fn missing(card: &Card) -> Vec<PathBuf> {
+    let base = base_directory(card);
+    references(&card.front, &base).into_iter()
+        .chain(references(&card.back, &base))
+        .map(|r| r.path)
+        .filter(|path| !path.is_file())
+        .collect()
+}
+
+// Copy media files into a folder, such as the `collection.media` folder of an Anki profile
+pub fn copy(media: &[Media], directory: &Path) -> Result<(), failure::Error> {
+    fs::create_dir_all(directory)?;
+    for file in media {
+        fs::copy(&file.path, directory.join(&file.name)).map_err(|e| {
+            failure::format_err!("couldn't copy `{}`: {}", file.path.display(), e)
+        })?;
+    }
+    Ok(())
+}
+
+// The directory relative media paths in a card are resolved from
+fn base_directory(card: &Card) -> PathBuf {
+    card.path.as_ref()
+        .and_then(|path| path.parent())
+        .map_or_else(PathBuf::new, Path::to_path_buf)
+}
+
+// Find the images, and links to audio, in a field that point at local files
+fn references(field: &str, base: &Path) -> Vec<Reference> {
+    let mut references: Vec<Reference> = Vec::new();
+    // Images are nested events, so skip anything inside a reference that was already found
+    let mut end = 0;
+    for (event, range) in Parser::new_ext(field, Options::empty()).into_offset_iter() {
+        if range.start < end {
+            if let (Event::Text(text), Some(reference)) = (&event, references.last_mut()) {
+                reference.alt.push_str(text);
+            }
+            continue;
+        }
+        let (dest_url, is_image) = match &event {
+            Event::Start(Tag::Image { dest_url, .. }) => (dest_url, true),
+            Event::Start(Tag::Link { dest_url, .. }) => (dest_url, false),
+            _ => continue,
+        };
+        if dest_url.contains(':') || dest_url.is_empty() {
+            continue;
+        }
+        let extension = Path::new(dest_url.as_ref()).extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_default();
+        let kind = if SOUND_EXTENSIONS.contains(&extension.as_str()) {
+            Kind::Sound
+        } else if is_image && IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            Kind::Image
+        } else {
+            continue;
+        };
+        end = range.end;
+        references.push(Reference { range, kind, path: base.join(dest_url.as_ref()), alt: String::new() });
+    }
+    references
+}
+
+// Pick a name for a file in the media folder. Files with the same name from different
+// directories are told apart by a hash of their path
+fn media_name(path: &Path, media: &[Media]) -> String {
+    let name = path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+    if !media.iter().any(|m| m.name == name) {
+        return name;
+    }
+    let hash = &sha1_smol::Sha1::from(path.to_string_lossy().as_bytes()).digest().to_string()[..8];
+    let stem = path.file_stem().map_or_else(String::new, |s| s.to_string_lossy().into_owned());
+    match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, hash, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, hash),
+    }
+}
+
+// The path used to tell whether two references are to the same file
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A card in `notes/bio.md` in a temporary directory holding `notes/images/cell.png`,
+    // `notes/audio/cell.mp3` and `other/cell.png`
+    fn card(name: &str, back: &str) -> (PathBuf, Card) {
+        let root = std::env::temp_dir().join(format!("md-to-anki-test-{}-{}", std::process::id(), name));
+        for file in ["notes/images/cell.png", "notes/audio/cell.mp3", "other/cell.png"] {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), file).unwrap();
+        }
+        let mut card = Card::new("Front".to_string(), back.to_string(), Vec::new());
+        card.path = Some(root.join("notes/bio.md"));
+        (root, card)
+    }
+
+    #[test]
+    fn relative_absolute_and_url_paths() {
+        let (root, card) = card("paths", "");
+        let absolute = root.join("other/cell.png");
+        let back = format!(
+            "![A cell](images/cell.png)<br>![Same](./images/cell.png)<br>![Other]({})<br>![Web](https://example.com/cell.png)<br>[Say it](audio/cell.mp3)",
+            absolute.display(),
+        );
+        let mut cards = [Card { back, ..card }];
+        let media = collect(&mut cards, false);
+        fs::remove_dir_all(&root).unwrap();
+
+        let names: Vec<&str> = media.iter().map(|m| m.name.as_str()).collect();
+        let hash = &sha1_smol::Sha1::from(absolute.to_string_lossy().as_bytes()).digest().to_string()[..8];
+        let other = format!("cell-{}.png", hash);
+        assert_eq!(names, ["cell.png", other.as_str(), "cell.mp3"]);
+        assert_eq!(media[1].path, absolute);
+        assert_eq!(cards[0].back, format!(
+            "![A cell](<cell.png>)<br>![Same](<cell.png>)<br>![Other](<{}>)<br>![Web](https://example.com/cell.png)<br>[sound:cell.mp3]",
+            other,
+        ));
+    }
+
+    #[test]
+    fn raw_fields_get_img_tags() {
+        let (root, card) = card("raw", "![A cell](images/cell.png) [not media](notes.txt)");
+        let mut cards = [card];
+        let media = collect(&mut cards, true);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(media.len(), 1);
+        assert_eq!(cards[0].back, "<img src=\"cell.png\"> [not media](notes.txt)");
+    }
+
+    #[test]
+    fn missing_files_are_reported_not_collected() {
+        let (root, card) = card("missing", "![Gone](images/gone.png) ![A cell](images/cell.png)");
+        let missing = missing(&card);
+        let mut cards = [card];
+        let media = collect(&mut cards, false);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(missing, [root.join("notes/images/gone.png")]);
+        assert_eq!(media.len(), 1);
+        assert_eq!(cards[0].back, "![Gone](<gone.png>) ![A cell](<cell.png>)");
+    }
+}
@@ -1,141 +1,718 @@
 use crate::card::*;
 
-use std::fmt;
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options as CmarkOptions, Parser, Tag, TagEnd};
+use std::{fmt, ops::Range};
 /// An enum for the different attributes a cards may have
+#[derive(PartialEq)]
 pub enum Attribute {
     Front,
     Back,
     MainTag,
-    SubTag,
+}
+
+/// A location in the markdown document. Both the line and column start at 0
+#[derive(Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+impl Position {
+    pub fn new(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+}
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line + 1, self.column + 1)
+    }
 }
 
 /// An enum for the different types of error that could occur while parsing the document
 pub enum ParseError {
-    /// When the parse function encounters a character it wasn't expecting.
-    /// Holds the line number and the character that caused the error
-    UnknownSymbol((usize, char)),
+    /// When a definition item doesn't start with a bold term.
+    /// Holds the position of the item
+    MissingTerm(Position),
     /// When the parse function encounters the end of the line before expecting it.
-    /// Holds the line number where the error occured
-    UnexpectedEndOfLine(usize),
+    /// Holds the position where the error occured
+    UnexpectedEndOfLine(Position),
     /// When the parse function encounters an unknown attruibute.
-    /// Holds the line number where the error occured
-    UnknownAttribute(usize),
+    /// Holds the position of the attribute
+    UnknownAttribute(Position),
+    /// When a cloze card doesn't contain anything to hide.
+    /// Holds the position of the card text
+    NoClozeDeletion(Position),
+    /// When a tag isn't followed by a list of cards.
+    /// Holds the position of the tag
+    MissingList(Position),
 }
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl ParseError {
+    // Where in the document the error occured
+    pub fn position(&self) -> Position {
         match self {
-            ParseError::UnknownSymbol(l) => {
-                write!(f, "Unknown symbol: {} on line {}", l.1, l.0)
-            },
-            ParseError::UnexpectedEndOfLine(l) => {
-                write!(f, "Unexpected end of line at line {}", l)
-            },
-            ParseError::UnknownAttribute(l) => {
-                write!(f, "Unknown attribute encountered on line {}", l)
-            }
+            ParseError::MissingTerm(p) => *p,
+            ParseError::UnexpectedEndOfLine(p) => *p,
+            ParseError::UnknownAttribute(p) => *p,
+            ParseError::NoClozeDeletion(p) => *p,
+            ParseError::MissingList(p) => *p,
+        }
+    }
+    // A description of the error without its position
+    pub fn message(&self) -> String {
+        match self {
+            ParseError::MissingTerm(_) => "Definition item needs a **term** before the colon".to_string(),
+            ParseError::UnexpectedEndOfLine(_) => "Unexpected end of line".to_string(),
+            ParseError::UnknownAttribute(_) => "Unknown attribute".to_string(),
+            ParseError::NoClozeDeletion(_) => "Cloze card without any deletions".to_string(),
+            ParseError::MissingList(_) => "Expected a list after this tag".to_string(),
         }
     }
 }
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message(), self.position())
+    }
+}
 
-#[derive(PartialEq)]
+// Hidden comments that give a card a permanent id, e.g. `<!-- id: Xq3#a1 -->`
+pub const ID_MARKER_START: &str = "<!-- id:";
+pub const ID_MARKER_END: &str = "-->";
+
+#[derive(PartialEq, Clone, Copy)]
 enum Type {
     Question,
-    Definition,
+    Definition(Direction),
+    Cloze,
+}
+
+/// Which way round definition cards are asked
+#[derive(PartialEq, Clone, Copy)]
+enum Direction {
+    /// Term to definition
+    Forward,
+    /// Definition to term
+    Reverse,
+    /// A card each way
+    Both,
+}
+
+/// A run of inline content in the document, such as a paragraph or the text of a list item
+struct Inline {
+    range: Range<usize>,
+    /// The first bold section, which is the term of a definition
+    strong: Option<Range<usize>>,
+    /// The language and text of a code block, which is kept whole instead of split into lines
+    code: Option<(String, String)>,
+}
+
+
+/// A list item that will become a card
+struct Item {
+    range: Range<usize>,
+    inlines: Vec<Inline>,
+    /// The id from an `<!-- id: ... -->` marker inside the item
+    id: Option<String>,
+}
+
+/// How the headings a card is under are turned into its tags
+#[derive(PartialEq, Clone, Copy, Default, clap::ValueEnum)]
+pub enum TagStyle {
+    /// A single Anki hierarchical tag, e.g. `Course::Chapter::Section`
+    #[default]
+    Hierarchical,
+    /// A separate tag for each heading, e.g. `Course Chapter Section`
+    Flat,
+    /// Both the hierarchical tag and the flat tags
+    Both,
 }
 
-pub fn parse_md(markdown: &str) -> Result<Vec<Card>, ParseError> {
+/// Settings that change how the document is turned into cards
+pub struct Options {
+    /// Put each card in a subdeck named after the headings it is under, e.g. `Main::Sub::Subsub`
+    pub heading_decks: bool,
+    pub tag_style: TagStyle,
+    /// What splits a line such as `Question? :: Answer` into a card. Empty to turn these off
+    pub inline_separator: String,
+    /// What splits a line such as `Term ::: Definition` into a card asked both ways round.
+    /// Empty to turn these off
+    pub reversed_separator: String,
+}
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            heading_decks: false,
+            tag_style: TagStyle::default(),
+            inline_separator: "::".to_string(),
+            reversed_separator: ":::".to_string(),
+        }
+    }
+}
+
+// Parse every card in the document. If a card can't be parsed it is skipped and parsing
+// carries on with the next one, so every error can be reported at once
+pub fn parse_md(markdown: &str, options: &Options) -> Result<Vec<Card>, Vec<ParseError>> {
     let mut cards:Vec<Card> = Vec::new();
-    let mut main_tag = "";
-    let mut sub_tag = "";
+    let mut errors = Vec::new();
+    let mut heading: Option<(HeadingLevel, String)> = None;
+    // Every heading the current position is under, along with its level
+    let mut headings: Vec<(HeadingLevel, String)> = Vec::new();
+    // The type of the most recent tag, and where it is, until the list it applies to starts
+    let mut pending: Option<(Type, usize)> = None;
     let mut current_type = None;
+    let mut list_depth = 0;
+    let mut item: Option<Item> = None;
+    let mut inline: Option<Inline> = None;
+    // The language and text of the code block being read
+    let mut code_block: Option<(String, String)> = None;
 
-    for (line_num, line) in markdown.lines().enumerate() {
-        let parsed_line = parse_line(line, line_num, current_type)?;
-        let attribute = parsed_line.0;
-        let value = parsed_line.1;
-        current_type = parsed_line.2;
-        match attribute {
-            Some(Attribute::Front) => {
-                let tag = main_tag.replace(" ", "_").replace(",", "") + " " +
-                          &sub_tag.replace(" ", "_").replace(",", "");
-                if current_type == Some(Type::Definition) {
-                    cards.push(Card::new("Define: ".to_string() + value.unwrap(),
-                     "".to_string(), tag));
-                } else {
-                    cards.push(Card::new(value.unwrap().to_string(), "".to_string(), tag));
+    for (event, range) in Parser::new_ext(markdown, CmarkOptions::ENABLE_MATH).into_offset_iter() {
+        let is_inline = match &event {
+            Event::Start(tag) => is_inline_tag(tag),
+            Event::End(tag) => is_inline_tag_end(tag),
+            Event::Text(_) | Event::Code(_) | Event::InlineHtml(_) | Event::SoftBreak
+                | Event::HardBreak | Event::InlineMath(_) | Event::DisplayMath(_)
+                | Event::FootnoteReference(_) | Event::TaskListMarker(_) => true,
+            Event::Html(_) | Event::Rule => false,
+        };
+
+        if let (Event::Html(html) | Event::InlineHtml(html), Some(item)) = (&event, item.as_mut()) {
+            if let Some(id) = find_id(html) {
+                item.id = Some(id);
+            }
+        }
+
+        if let (Some((_, code)), true) = (code_block.as_mut(), is_inline) {
+            if let Event::Text(text) = &event {
+                code.push_str(text);
+            }
+            continue;
+        }
+
+        if is_inline {
+            if let Some((_, text)) = heading.as_mut() {
+                if let Event::Text(t) | Event::Code(t) | Event::InlineMath(t) = &event {
+                    text.push_str(t);
+                }
+            }
+            if heading.is_none() {
+                let run = inline.get_or_insert(Inline { range: range.clone(), strong: None, code: None });
+                run.range.end = run.range.end.max(range.end);
+                if let (Event::Start(Tag::Strong), None) = (&event, &run.strong) {
+                    run.strong = Some(range);
+                }
+            }
+            continue;
+        }
+
+        // Anything else is the boundary of a block, which ends the current run of inline content.
+        // Runs outside of the lists beneath tags can still hold inline cards
+        if let Some(run) = inline.take() {
+            match item.as_mut() {
+                Some(item) => item.inlines.push(run),
+                None => {
+                    let names: Vec<&str> = headings.iter().map(|(_, h)| h.as_str()).collect();
+                    let tags = heading_tags(&names, options.tag_style);
+                    for mut card in parse_inline_cards(markdown, run.range, &tags, options) {
+                        if options.heading_decks && !names.is_empty() {
+                            card.deck = Some(names.join("::"));
+                        }
+                        cards.push(card);
+                    }
+                },
+            }
+        }
+
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or("").to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code_block = Some((language, String::new()));
+            },
+            Event::End(TagEnd::CodeBlock) => {
+                if let (Some(code), Some(item)) = (code_block.take(), item.as_mut()) {
+                    item.inlines.push(Inline { range, strong: None, code: Some(code) });
+                }
+            },
+            Event::Start(Tag::Heading { level, .. }) => {
+                heading = Some((level, String::new()));
+            },
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((level, text)) = heading.take() {
+                    while headings.last().is_some_and(|(l, _)| *l >= level) {
+                        headings.pop();
+                    }
+                    headings.push((level, text.trim().to_string()));
+                }
+            },
+            Event::Start(Tag::List(_)) => {
+                if list_depth == 0 {
+                    current_type = pending.take().map(|(t, _)| t);
+                }
+                list_depth += 1;
+            },
+            Event::End(TagEnd::List(_)) => {
+                list_depth -= 1;
+                if list_depth == 0 {
+                    current_type = None;
+                }
+            },
+            Event::Start(Tag::Item) if list_depth == 1 && current_type.is_some() => {
+                item = Some(Item { range, inlines: Vec::new(), id: None });
+            },
+            Event::End(TagEnd::Item) if list_depth == 1 => {
+                if let (Some(card_type), Some(item)) = (current_type, item.take()) {
+                    let names: Vec<&str> = headings.iter().map(|(_, h)| h.as_str()).collect();
+                    match parse_card(markdown, card_type, item, heading_tags(&names, options.tag_style)) {
+                        Ok(mut card) => {
+                            if options.heading_decks && !names.is_empty() {
+                                card.deck = Some(names.join("::"));
+                            }
+                            cards.push(card)
+                        },
+                        Err(e) => errors.push(e),
+                    }
+                }
+                // A blank line after an item ends the list the tag applies to
+                if ends_with_blank_line(&markdown[range]) {
+                    current_type = None;
                 }
             },
-            Some(Attribute::Back) => {
-                if let Some(c) = cards.last_mut() {
-                    c.add_to_back(&value.unwrap());
+            Event::Start(Tag::Paragraph) if list_depth == 0 => {
+                // A tag is a paragraph on its own, and must be followed by a list
+                let text = markdown[range.clone()].trim();
+                let marker = text.strip_prefix("[](").and_then(|t| t.strip_suffix(')'));
+                if let Some((_, position)) = pending.take() {
+                    errors.push(ParseError::MissingList(to_position(markdown, position)));
+                }
+                let words: Option<Vec<&str>> = marker.map(|m| m.split_whitespace().collect());
+                match words.as_deref() {
+                    Some(["question"]) => { pending = Some((Type::Question, range.start)) },
+                    Some(["definition"]) | Some(["definition", "forward"]) => {
+                        pending = Some((Type::Definition(Direction::Forward), range.start))
+                    },
+                    Some(["definition", "reverse"]) => {
+                        pending = Some((Type::Definition(Direction::Reverse), range.start))
+                    },
+                    Some(["definition", "both"]) => {
+                        pending = Some((Type::Definition(Direction::Both), range.start))
+                    },
+                    Some(["cloze"]) => { pending = Some((Type::Cloze, range.start)) },
+                    Some(_) => {
+                        let position = range.start + text.find('(').unwrap_or(0) + 1;
+                        errors.push(ParseError::UnknownAttribute(to_position(markdown, position)));
+                    },
+                    None => {}
+                }
+            },
+            Event::Start(_) if list_depth == 0 => {
+                if let Some((_, position)) = pending.take() {
+                    errors.push(ParseError::MissingList(to_position(markdown, position)));
                 }
             },
-            Some(Attribute::MainTag) => { main_tag = &value.unwrap(); },
-            Some(Attribute::SubTag) => { sub_tag = &value.unwrap(); },
-            None => {}
+            _ => {}
         }
     }
+    if let Some((_, position)) = pending {
+        errors.push(ParseError::MissingList(to_position(markdown, position)));
+    }
+
+    if errors.is_empty() {
+        Ok(cards)
+    } else {
+        Err(errors)
+    }
+}
 
-    Ok(cards)
+fn ends_with_blank_line(text: &str) -> bool {
+    text.lines().next_back().is_some_and(|line| line.trim().is_empty())
 }
 
-type ParsedLine<'a> = Result<(Option<Attribute>, Option<&'a str>, Option<Type>), ParseError>;
-fn parse_line(line: &str, line_num: usize, card_type: Option<Type>) -> ParsedLine {
-    let mut line_iterator = line.chars();
-    match card_type {
-        Some(Type::Question) => {
-            match line_iterator.next() {
-                Some('-') => { Ok((Some(Attribute::Front), Some(&line[2..]), Some(Type::Question))) },
-                Some(' ') => { Ok((Some(Attribute::Back), Some(&line[4..]), Some(Type::Question))) },
-                Some(x) => { Err(ParseError::UnknownSymbol((line_num, x))) },
-                None => { Ok((None, None, None)) },
+// Turn the headings a card is under into its tags. Tags can't contain spaces or commas
+fn heading_tags(headings: &[&str], style: TagStyle) -> Vec<String> {
+    let names: Vec<String> = headings.iter()
+        .map(|h| h.replace(" ", "_").replace(",", ""))
+        .collect();
+    let mut tags = Vec::new();
+    if style != TagStyle::Flat && !names.is_empty() {
+        tags.push(names.join("::"));
+    }
+    if style != TagStyle::Hierarchical {
+        // A single heading is the same tag both ways, and headings can repeat
+        for name in names {
+            if !tags.contains(&name) {
+                tags.push(name);
             }
+        }
+    }
+    tags
+}
+
+// Make a card from the inline content of a list item following a tag
+fn parse_card(markdown: &str, card_type: Type, item: Item, tags: Vec<String>) -> Result<Card, ParseError> {
+    let inlines = &item.inlines;
+    let first = match inlines.first() {
+        Some(first) => first,
+        None => return Err(ParseError::UnexpectedEndOfLine(to_position(markdown, item.range.start))),
+    };
+    let mut back_lines: Vec<Line> = inlines[1..].iter()
+        .flat_map(|i| match &i.code {
+            Some((language, code)) => vec![Line::Code { language: language.clone(), code: code.clone() }],
+            None => lines(&markdown[i.range.clone()]).into_iter().map(Line::Text).collect(),
+        })
+        .collect();
+
+    let mut card = match card_type {
+        Type::Question => {
+            Card::new(lines(&markdown[first.range.clone()]).join(" "), "".to_string(), tags)
         },
-        Some(Type::Definition) => {
-            match line_iterator.next() {
-                Some('-') => {
-                    for (i, char) in line_iterator.enumerate() {
-                        if char == '*' && i > 4 {
-                            return Ok((Some(Attribute::Front), Some(&line[4..=i]), Some(Type::Definition)))
-                        }
-                    }
-                    Err(ParseError::UnexpectedEndOfLine(line_num))
-                },
-                Some(_) => { Ok((Some(Attribute::Back), Some(&line), None))},
-                None => { Err(ParseError::UnexpectedEndOfLine(line_num)) }
+        Type::Definition(direction) => {
+            // The term is the bold text at the start of the item, and the definition follows it
+            let strong = match &first.strong {
+                Some(strong) if strong.start == first.range.start => strong,
+                _ => return Err(ParseError::MissingTerm(to_position(markdown, first.range.start))),
+            };
+            let term = &markdown[strong.start + 2..strong.end - 2];
+            let definition = strip_id(&markdown[strong.end..first.range.end]);
+            let definition = definition.trim_start_matches(|c: char| c == ':' || c.is_whitespace());
+            back_lines.splice(0..0, lines(definition).into_iter().map(Line::Text));
+            if back_lines.is_empty() {
+                return Err(ParseError::UnexpectedEndOfLine(to_position(markdown, first.range.end)));
             }
-        },
-        None => {
-            match line_iterator.next() {
-                Some('#') => {
-                    match line_iterator.next() {
-                        Some(' ') => { Ok((Some(Attribute::MainTag), Some(&line[2..]), None)) },
-                        Some('#') => {
-                            for (i, char) in line_iterator.enumerate() {
-                                if char == ' ' {
-                                    return Ok((Some(Attribute::SubTag), Some(&line[i+3..]), None))
-                                }
-                            }
-                            Err(ParseError::UnexpectedEndOfLine(line_num))
-                        },
-                        Some(x) => { Err(ParseError::UnknownSymbol((line_num, x))) },
-                        None => { Err(ParseError::UnexpectedEndOfLine(line_num))},
+            match direction {
+                Direction::Forward => Card::new("Define: ".to_string() + term, "".to_string(), tags),
+                Direction::Reverse => {
+                    // The definition goes on the front, laid out the same way it would be on the back
+                    let mut card = Card::new("".to_string(), "".to_string(), tags);
+                    for line in back_lines.drain(..) {
+                        card.add_line_to_back(line);
                     }
+                    card.front = card.back.trim_end_matches("<br>").to_string();
+                    card.back = "".to_string();
+                    back_lines = vec![Line::Text(term.to_string())];
+                    card
                 },
-                Some('[') => {
-                    for (i, char) in line_iterator.enumerate() {
-                        if char == ')' {
-                            match &line[3..=i] {
-                                "question" => { return Ok((None, None, Some(Type::Question)))},
-                                "definition" => { return Ok((None, None, Some(Type::Definition)))},
-                                _ => { return Err(ParseError::UnknownAttribute(line_num)) }
-                            }
-                        }
-                    }
-                    Err(ParseError::UnexpectedEndOfLine(line_num))
+                Direction::Both => {
+                    let mut card = Card::new(term.to_string(), "".to_string(), tags);
+                    card.note_type = NoteType::BasicAndReversed;
+                    card
                 },
-                _ => { Ok((None, None, None)) }
             }
+        },
+        Type::Cloze => {
+            let mut card = Card::new(to_cloze(&lines(&markdown[first.range.clone()]).join(" ")),
+                "".to_string(), tags);
+            if card.cloze_numbers().is_empty() {
+                return Err(ParseError::NoClozeDeletion(to_position(markdown, first.range.start)));
+            }
+            card.note_type = NoteType::Cloze;
+            card
+        },
+    };
+    for line in back_lines {
+        card.add_line_to_back(line);
+    }
+    card.id = item.id;
+    card.span = Some(item.range);
+    card.syntax = Some(match card_type {
+        Type::Definition(direction) => Syntax::Definition { reversed: direction == Direction::Reverse },
+        Type::Question | Type::Cloze => Syntax::Item,
+    });
+    Ok(card)
+}
+
+// Make a card from every line of some inline content that is split in two by a separator,
+// e.g. `Question? :: Answer` or `Term ::: Definition`
+fn parse_inline_cards(markdown: &str, range: Range<usize>, tags: &[String], options: &Options) -> Vec<Card> {
+    let mut cards = Vec::new();
+    let mut start = range.start;
+    for line in markdown[range.clone()].split_inclusive('\n') {
+        let span = start..start + line.trim_end().len();
+        start += line.len();
+        let text = strip_id(line);
+        let (i, separator, note_type) = match find_separator(&text, &options.reversed_separator) {
+            Some(i) => (i, &options.reversed_separator, NoteType::BasicAndReversed),
+            None => match find_separator(&text, &options.inline_separator) {
+                Some(i) => (i, &options.inline_separator, NoteType::Basic),
+                None => continue,
+            },
+        };
+        let front = text[..i].trim();
+        let back = text[i + separator.len()..].trim();
+        if front.is_empty() || back.is_empty() {
+            continue;
         }
+        let mut card = Card::new(front.to_string(), "".to_string(), tags.to_vec());
+        card.add_to_back(back);
+        card.note_type = note_type;
+        card.id = find_id(line);
+        card.span = Some(span);
+        card.syntax = Some(Syntax::Inline { separator: separator.clone() });
+        cards.push(card);
+    }
+    cards
+}
+
+// Find where a separator splits a line. Separators inside inline code or `{{c1::...}}` cloze
+// deletions don't count, and a separator needs whitespace or the end of the line on both sides,
+// so `::` isn't found in `:::` and prose mentioning `std::vector` or `C++::x` isn't split
+fn find_separator(line: &str, separator: &str) -> Option<usize> {
+    if separator.is_empty() {
+        return None;
+    }
+    let mut code = false;
+    let mut braces = 0;
+    let mut i = 0;
+    while let Some(c) = line[i..].chars().next() {
+        let rest = &line[i..];
+        if !code && braces == 0 && rest.starts_with(separator) {
+            let before = line[..i].chars().last();
+            let after = rest[separator.len()..].chars().next();
+            if before.is_none_or(char::is_whitespace) && after.is_none_or(char::is_whitespace) {
+                return Some(i);
+            }
+        }
+        if c == '`' {
+            code = !code;
+        } else if !code && rest.starts_with("{{") {
+            braces += 1;
+            i += 1;
+        } else if !code && braces > 0 && rest.starts_with("}}") {
+            braces -= 1;
+            i += 1;
+        }
+        i += c.len_utf8();
+    }
+    None
+}
+
+// The non-empty lines of some text with their indentation and any id markers removed.
+// Display math written over several lines is kept together on one line
+fn lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut in_math = false;
+    for line in strip_id(text).lines().map(str::trim) {
+        match lines.last_mut() {
+            Some(last) if in_math => {
+                last.push(' ');
+                last.push_str(line);
+            },
+            _ if line.is_empty() => continue,
+            _ => lines.push(line.to_string()),
+        }
+        in_math ^= line.matches("$$").count() % 2 == 1;
+    }
+    lines
+}
+
+// Read the id out of an `<!-- id: ... -->` marker
+fn find_id(html: &str) -> Option<String> {
+    let start = html.find(ID_MARKER_START)? + ID_MARKER_START.len();
+    let end = html[start..].find(ID_MARKER_END)? + start;
+    let id = html[start..end].trim();
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_string())
+    }
+}
+
+// Remove every `<!-- id: ... -->` marker from some text
+fn strip_id(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(ID_MARKER_START) {
+        match rest[start..].find(ID_MARKER_END) {
+            Some(end) => {
+                stripped += rest[..start].trim_end_matches(' ');
+                rest = &rest[start + end + ID_MARKER_END.len()..];
+            },
+            None => break,
+        }
+    }
+    stripped + rest
+}
+
+// Find the line and column of a byte offset into the document
+pub fn to_position(markdown: &str, offset: usize) -> Position {
+    let before = &markdown[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position::new(before.matches('\n').count(), before[line_start..].chars().count())
+}
+
+fn is_inline_tag(tag: &Tag) -> bool {
+    matches!(tag, Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Superscript
+        | Tag::Subscript | Tag::Link { .. } | Tag::Image { .. })
+}
+
+fn is_inline_tag_end(tag: &TagEnd) -> bool {
+    matches!(tag, TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough | TagEnd::Superscript
+        | TagEnd::Subscript | TagEnd::Link | TagEnd::Image)
+}
+
+// Turn the `==highlighted==` and `**bold**` parts of a line into cloze deletions, given the
+// lowest numbers not used by any `{{cN::...}}` deletions that were already written out.
+// Markers inside `$...$` math are left alone
+fn to_cloze(text: &str) -> String {
+    let math: Vec<Range<usize>> = Parser::new_ext(text, CmarkOptions::ENABLE_MATH).into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::InlineMath(_) | Event::DisplayMath(_)))
+        .map(|(_, range)| range)
+        .collect();
+    let find = |from: usize, marker: &str| {
+        text[from..].match_indices(marker)
+            .map(|(i, _)| from + i)
+            .find(|i| !math.iter().any(|m| m.contains(i)))
+    };
+    let mut used = cloze_numbers(text);
+    let mut cloze = String::new();
+    let mut last = 0;
+    loop {
+        let next = ["==", "**"].iter()
+            .filter_map(|marker| find(last, marker).map(|i| (i, *marker)))
+            .min_by_key(|(i, _)| *i);
+        let (start, marker) = match next {
+            Some(n) => n,
+            None => break,
+        };
+        match find(start + 2, marker) {
+            Some(end) if end > start + 2 => {
+                let number = (1..).find(|n| !used.contains(n)).unwrap_or(1);
+                used.push(number);
+                cloze += &text[last..start];
+                cloze += &format!("{{{{c{}::{}}}}}", number, &text[start + 2..end]);
+                last = end + 2;
+            },
+            _ => break,
+        }
+    }
+    cloze + &text[last..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(markdown: &str) -> Vec<Card> {
+        match parse_md(markdown, &Options::default()) {
+            Ok(cards) => cards,
+            Err(errors) => panic!("{}", errors[0]),
+        }
+    }
+
+    fn fronts(cards: &[Card]) -> Vec<&str> {
+        cards.iter().map(|card| card.front.as_str()).collect()
+    }
+
+    #[test]
+    fn tight_list_is_all_cards() {
+        let cards = parse("[](question)\n- One\n  - 1\n- Two\n  - 2\n- Three\n");
+        assert_eq!(fronts(&cards), ["One", "Two", "Three"]);
+        assert_eq!(cards[0].back, "1<br>");
+        assert_eq!(cards[2].back, "");
+    }
+
+    #[test]
+    fn blank_line_ends_the_list() {
+        let cards = parse("[](question)\n- One\n  - 1\n\n- Not a card\n- Nor this\n");
+        assert_eq!(fronts(&cards), ["One"]);
+        // A blank line after a nested list ends it too
+        let cards = parse("[](question)\n- One\n  - 1\n  - 2\n\n- Not a card\n");
+        assert_eq!(fronts(&cards), ["One"]);
+        assert_eq!(cards[0].back, "1<br>2<br>");
+    }
+
+    #[test]
+    fn example_ignores_untagged_list() {
+        let cards = parse(include_str!("../example.md"));
+        assert_eq!(fronts(&cards), [
+            "Question", "Another question", "Define: Thing to define",
+            "The capital of France is {{c1::Paris}}", "{{c1::Rust}} was first released in {{c2::2015}}",
+        ]);
+        assert_eq!(cards[1].back, "multi-line<br>answer<br>");
+        assert_eq!(cards[2].back, "The definition<br>");
+        assert_eq!(cards[2].tags, ["Main_Tag::Sub_Tag_2"]);
+    }
+
+    #[test]
+    fn heading_tags_both_ways() {
+        let markdown = "# Bio\n\n[](question)\n- One\n\n## Cells\n\n[](question)\n- Two\n\n### Bio\n\n[](question)\n- Three\n";
+        let options = Options { tag_style: TagStyle::Both, ..Options::default() };
+        let cards = parse_md(markdown, &options).unwrap_or_else(|errors| panic!("{}", errors[0]));
+        assert_eq!(cards[0].tags, ["Bio"]);
+        assert_eq!(cards[1].tags, ["Bio::Cells", "Bio", "Cells"]);
+        assert_eq!(cards[2].tags, ["Bio::Cells::Bio", "Bio", "Cells"]);
+        let options = Options { tag_style: TagStyle::Flat, ..Options::default() };
+        let cards = parse_md(markdown, &options).unwrap_or_else(|errors| panic!("{}", errors[0]));
+        assert_eq!(cards[2].tags, ["Bio", "Cells"]);
+    }
+
+    #[test]
+    fn cloze_numbering() {
+        assert_eq!(to_cloze("==Paris== is in **France**"), "{{c1::Paris}} is in {{c2::France}}");
+        // New deletions fill in the numbers that aren't used yet
+        assert_eq!(to_cloze("{{c2::x}} and **y**"), "{{c2::x}} and {{c1::y}}");
+        assert_eq!(to_cloze("{{c1::a}} {{c3::b}} ==c== ==d=="), "{{c1::a}} {{c3::b}} {{c2::c}} {{c4::d}}");
+        // Markers in math and unclosed markers stay as they are
+        assert_eq!(to_cloze("$a**b**c$ **d** ==e"), "$a**b**c$ {{c1::d}} ==e");
+    }
+
+    #[test]
+    fn tab_indentation() {
+        let cards = parse("[](question)\n-\tQuestion\n\t- Answer\n\t- More\n");
+        assert_eq!(fronts(&cards), ["Question"]);
+        assert_eq!(cards[0].back, "Answer<br>More<br>");
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let markdown = "# Größe\n\n[](question)\n- Wie groß ist 大きい?\n  - Sehr — ✓\n\n[](definition)\n- **Ünïcode**: naïve café\n";
+        let cards = parse(markdown);
+        assert_eq!(fronts(&cards), ["Wie groß ist 大きい?", "Define: Ünïcode"]);
+        assert_eq!(cards[0].back, "Sehr — ✓<br>");
+        assert_eq!(cards[1].back, "naïve café<br>");
+        assert_eq!(cards[0].tags, ["Größe"]);
+        let span = cards[0].span.clone().unwrap();
+        assert!(markdown[span].starts_with("- Wie groß"));
+    }
+
+    #[test]
+    fn error_position_counts_characters() {
+        let errors = match parse_md("ä ö ü\n\n[](quëstion)\n- x\n", &Options::default()) {
+            Ok(_) => panic!("expected an error"),
+            Err(errors) => errors,
+        };
+        assert!(matches!(errors[0], ParseError::UnknownAttribute(_)));
+        assert_eq!(errors[0].position().to_string(), "3:4");
+    }
+
+    #[test]
+    fn definition_needs_a_term() {
+        let errors = match parse_md("[](definition)\n- **Term**: fine\n- no term: here\n", &Options::default()) {
+            Ok(_) => panic!("expected an error"),
+            Err(errors) => errors,
+        };
+        assert!(matches!(errors[0], ParseError::MissingTerm(_)));
+        assert_eq!(errors[0].to_string(), "Definition item needs a **term** before the colon at 3:3");
+    }
+
+    #[test]
+    fn inline_cards() {
+        let cards = parse("What is 2+2? :: Four\nOsmosis ::: Diffusion\nWie groß?\t::\tSehr groß\n");
+        assert_eq!(fronts(&cards), ["What is 2+2?", "Osmosis", "Wie groß?"]);
+        assert!(cards[0].note_type == NoteType::Basic);
+        assert!(cards[1].note_type == NoteType::BasicAndReversed);
+        assert_eq!(cards[2].back, "Sehr groß<br>");
+    }
+
+    #[test]
+    fn separator_between_words_is_not_a_card() {
+        let cards = parse("Some prose with std::vector in it.\nBio::Cells\nGröße::Größe\n`a::b` and {{c1::x}}\n");
+        assert!(cards.is_empty());
+    }
+
+    #[test]
+    fn separator_needs_spaces_around_it() {
+        let cards = parse("C++::x is a path\nWhat?::No\nWhat? ::No\nWhat?:: No\nTerm:::Definition\n");
+        assert!(cards.is_empty());
     }
 }
@@ -0,0 +1,199 @@
+//! Converting the markdown written in card fields into the HTML Anki displays
+use pulldown_cmark::{html, CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use std::sync::OnceLock;
+use syntect::{highlighting::{Theme, ThemeSet}, html::highlighted_html_for_string, parsing::SyntaxSet};
+
+// Link schemes that could run code when the card is shown
+const UNSAFE_SCHEMES: [&str; 3] = ["javascript:", "vbscript:", "data:"];
+// A light theme, to match the white background of the default card style
+const CODE_THEME: &str = "InspiredGitHub";
+// Cards are centred by default, which code shouldn't be
+const CODE_BLOCK_START: &str = "<div style=\"text-align: left\">";
+const CODE_BLOCK_END: &str = "</div>";
+
+// Render a field to HTML. Raw HTML in the field is escaped, apart from the `<br>` line breaks
+// used to join the lines of the back, and links that could run scripts are removed.
+// `$...$` and `$$...$$` math is written with the delimiters Anki's MathJax looks for, and code
+// blocks in a language that is recognised are highlighted with inline styles
+pub fn to_html(field: &str) -> String {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_MATH;
+    let events: Vec<Event> = Parser::new_ext(field, options).collect();
+
+    // A field that is a single paragraph is shown without the paragraph's margins
+    let paragraphs = events.iter().filter(|e| matches!(e, Event::Start(Tag::Paragraph))).count();
+    let blocks = events.iter().filter(|e| matches!(e, Event::Start(t) if is_block(t))).count();
+    let unwrap = paragraphs == 1 && blocks == 1;
+
+    let mut unsafe_links = Vec::new();
+    let mut sanitized = Vec::new();
+    // The events of the code block being read, with its language and text
+    let mut code_block: Option<(Vec<Event>, String, String)> = None;
+    for event in events {
+        if let Some((block, _, code)) = code_block.as_mut() {
+            if let Event::Text(text) = &event {
+                code.push_str(text);
+            }
+            let end = matches!(event, Event::End(TagEnd::CodeBlock));
+            block.push(event);
+            if end {
+                let (block, language, code) = code_block.take().unwrap_or_default();
+                sanitized.push(Event::Html(CODE_BLOCK_START.into()));
+                match highlight(&language, &code) {
+                    Some(html) => sanitized.push(Event::Html(html.into())),
+                    None => sanitized.extend(block),
+                }
+                sanitized.push(Event::Html(CODE_BLOCK_END.into()));
+            }
+            continue;
+        }
+        match event {
+            Event::Start(Tag::CodeBlock(ref kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or("").to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code_block = Some((vec![event], language, String::new()));
+            },
+            Event::Start(Tag::Paragraph) | Event::End(TagEnd::Paragraph) if unwrap => {},
+            Event::Html(text) | Event::InlineHtml(text) => {
+                if is_line_break(&text) {
+                    sanitized.push(Event::InlineHtml(text));
+                } else {
+                    sanitized.push(Event::Text(text));
+                }
+            },
+            // Unsafe links and images are replaced by their text
+            Event::Start(Tag::Link { ref dest_url, .. }) | Event::Start(Tag::Image { ref dest_url, .. })
+                if is_unsafe(dest_url) => {
+                unsafe_links.push(true);
+            },
+            Event::Start(Tag::Link { .. }) | Event::Start(Tag::Image { .. }) => {
+                unsafe_links.push(false);
+                sanitized.push(event);
+            },
+            Event::End(TagEnd::Link) | Event::End(TagEnd::Image) => {
+                if !unsafe_links.pop().unwrap_or(false) {
+                    sanitized.push(event);
+                }
+            },
+            Event::InlineMath(math) => sanitized.push(Event::Text(inline_math(&math).into())),
+            Event::DisplayMath(math) => sanitized.push(Event::Text(display_math(&math).into())),
+            event => sanitized.push(event),
+        }
+    }
+
+    let mut output = String::new();
+    html::push_html(&mut output, sanitized.into_iter());
+    output.trim_end().to_string()
+}
+
+// Rewrite the math in a field for Anki's MathJax without touching the rest of the markdown,
+// for fields that are exported raw
+pub fn to_mathjax(field: &str) -> String {
+    let mut output = String::new();
+    let mut last = 0;
+    for (event, range) in Parser::new_ext(field, Options::ENABLE_MATH).into_offset_iter() {
+        let math = match event {
+            Event::InlineMath(math) => inline_math(&math),
+            Event::DisplayMath(math) => display_math(&math),
+            _ => continue,
+        };
+        output += &field[last..range.start];
+        output += &math;
+        last = range.end;
+    }
+    output + &field[last..]
+}
+
+// Highlight some code as HTML with inline styles, if its language is known. The spans go in a
+// `<pre><code>` block like unhighlighted code, which is what Anki's styles look for
+fn highlight(language: &str, code: &str) -> Option<String> {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    if language.is_empty() {
+        return None;
+    }
+    let syntaxes = SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines);
+    let theme = THEME.get_or_init(|| ThemeSet::load_defaults().themes.remove(CODE_THEME).unwrap_or_default());
+    let syntax = syntaxes.find_syntax_by_token(language)?;
+    let html = highlighted_html_for_string(code, syntaxes, syntax, theme).ok()?;
+    let open = html.find('>')? + 1;
+    let close = html.rfind("</pre>")?;
+    // The line break after `<pre>` is ignored, but wouldn't be after `<code>`
+    let spans = html[open..close].strip_prefix('\n').unwrap_or(&html[open..close]);
+    let class: String = language.chars().filter(|c| c.is_alphanumeric() || "+#-_".contains(*c)).collect();
+    Some(format!("{}<code class=\"language-{}\">{}</code>{}", &html[..open], class, spans, &html[close..]))
+}
+
+fn inline_math(math: &str) -> String {
+    format!("\\({}\\)", math)
+}
+
+fn display_math(math: &str) -> String {
+    format!("\\[{}\\]", math)
+}
+
+fn is_line_break(html: &str) -> bool {
+    let tag: String = html.chars().filter(|c| !c.is_whitespace()).collect();
+    tag.eq_ignore_ascii_case("<br>") || tag.eq_ignore_ascii_case("<br/>")
+}
+
+fn is_unsafe(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    UNSAFE_SCHEMES.iter().any(|scheme| url.starts_with(scheme))
+}
+
+fn is_block(tag: &Tag) -> bool {
+    matches!(tag, Tag::Paragraph | Tag::Heading { .. } | Tag::BlockQuote(_) | Tag::CodeBlock(_)
+        | Tag::List(_) | Tag::HtmlBlock | Tag::Table(_) | Tag::FootnoteDefinition(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsafe_links_are_replaced_by_their_text() {
+        assert_eq!(to_html("[x](javascript:alert(1))"), "x");
+        assert_eq!(to_html("[x]( JavaScript:alert(1))"), "x");
+        assert_eq!(to_html("[x](vbscript:msgbox)"), "x");
+        assert_eq!(to_html("![i](data:image/svg+xml;base64,AA) [**bold**](javascript:x)"), "i <strong>bold</strong>");
+        assert_eq!(to_html("[ok](https://example.com)"), "<a href=\"https://example.com\">ok</a>");
+    }
+
+    #[test]
+    fn raw_html_is_escaped() {
+        assert_eq!(to_html("<script>alert(1)</script>"), "&lt;script&gt;alert(1)&lt;/script&gt;");
+        assert_eq!(to_html("a<br onmouseover=alert(1)>b"), "a&lt;br onmouseover=alert(1)&gt;b");
+        assert_eq!(to_html("<img src=x onerror=alert(1)>"), "&lt;img src=x onerror=alert(1)&gt;");
+    }
+
+    #[test]
+    fn code_blocks_are_highlighted_inside_code() {
+        let html = to_html("```rust\nfn main() {}\n```");
+        assert!(html.starts_with("<div style=\"text-align: left\"><pre style=\"background-color:#ffffff;\"><code class=\"language-rust\"><span "), "{}", html);
+        assert!(html.ends_with("</span></code></pre>\n</div>"), "{}", html);
+        // Languages that aren't recognised are left to the markdown renderer, escaped
+        assert_eq!(to_html("```zzz\n<b>\n```"), "<div style=\"text-align: left\">\n<pre><code class=\"language-zzz\">&lt;b&gt;\n</code></pre>\n</div>");
+    }
+
+    #[test]
+    fn math_uses_mathjax_delimiters() {
+        assert_eq!(to_html("$x^2$ and $a<b$"), "\\(x^2\\) and \\(a&lt;b\\)");
+        assert_eq!(to_html("$$\\sum_i x_i$$"), "\\[\\sum_i x_i\\]");
+        // Dollars in code and escaped dollars aren't math
+        assert_eq!(to_html("`$x$` costs \\$5"), "<code>$x$</code> costs $5");
+    }
+
+    #[test]
+    fn raw_fields_only_change_the_math() {
+        assert_eq!(to_mathjax("**a** $x$ b<br>$$\ny_1\n$$"), "**a** \\(x\\) b<br>\\[\ny_1\n\\]");
+        assert_eq!(to_mathjax("`$x$` and \\$5"), "`$x$` and \\$5");
+        assert_eq!(to_mathjax("no math"), "no math");
+    }
+
+    #[test]
+    fn line_breaks_are_kept() {
+        assert_eq!(to_html("a<br>b<BR/>c<br />d"), "a<br>b<BR/>c<br />d");
+    }
+}
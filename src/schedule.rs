@@ -0,0 +1,221 @@
+//! A simple SM-2 style scheduler for studying cards in the terminal. The state of every card
+//! is kept in a JSON sidecar file, keyed by the GUID of the card, so it survives the markdown
+//! being edited
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+// Eases are in permille like Anki's, so 2500 multiplies the interval by 2.5
+const STARTING_EASE: u32 = 2500;
+const MINIMUM_EASE: u32 = 1300;
+
+/// How well a card was remembered
+#[derive(PartialEq, Clone, Copy)]
+pub enum Answer {
+    Again,
+    Hard,
+    Good,
+    Easy,
+}
+
+/// How a card has been studied so far
+#[derive(Clone, Copy)]
+pub struct Review {
+    /// The day the card should be studied next, counted from the Unix epoch
+    pub due: u64,
+    /// The number of days between the last two reviews
+    pub interval: u64,
+    pub ease: u32,
+    /// How many times in a row the card has been remembered
+    pub reps: u32,
+    /// How many times the card has been forgotten after being learnt
+    pub lapses: u32,
+}
+impl Default for Review {
+    fn default() -> Review {
+        Review { due: 0, interval: 0, ease: STARTING_EASE, reps: 0, lapses: 0 }
+    }
+}
+impl Review {
+    // Schedule the next review of the card from how well it was remembered today
+    pub fn answer(&mut self, answer: Answer, today: u64) {
+        let interval = self.interval as f64;
+        let ease = f64::from(self.ease) / 1000.0;
+        self.interval = match answer {
+            Answer::Again => {
+                if self.reps > 0 {
+                    self.lapses += 1;
+                }
+                self.reps = 0;
+                self.ease = self.ease.saturating_sub(200).max(MINIMUM_EASE);
+                0
+            },
+            Answer::Hard => {
+                self.reps += 1;
+                self.ease = self.ease.saturating_sub(150).max(MINIMUM_EASE);
+                (interval * 1.2).round().max(1.0) as u64
+            },
+            Answer::Good => {
+                self.reps += 1;
+                match self.reps {
+                    1 => 1,
+                    2 => 3,
+                    _ => (interval * ease).round().max(interval + 1.0) as u64,
+                }
+            },
+            Answer::Easy => {
+                self.reps += 1;
+                self.ease += 150;
+                match self.reps {
+                    1 => 4,
+                    _ => (interval * ease * 1.3).round().max(interval + 1.0) as u64,
+                }
+            },
+        };
+        self.due = today + self.interval;
+    }
+}
+
+/// The reviews of every card that has been studied, and the file they are kept in
+pub struct Schedule {
+    path: PathBuf,
+    reviews: HashMap<String, Review>,
+}
+impl Schedule {
+    // Read the schedule from a sidecar file. A file that doesn't exist yet is an empty schedule
+    pub fn load(path: &Path) -> Result<Schedule, failure::Error> {
+        let mut reviews = HashMap::new();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(failure::format_err!("couldn't read `{}`: {}", path.display(), e)),
+        };
+        if !text.trim().is_empty() {
+            let value: Value = serde_json::from_str(&text)
+                .map_err(|e| failure::format_err!("couldn't read `{}`: {}", path.display(), e))?;
+            for (key, review) in value.as_object().into_iter().flatten() {
+                let number = |name: &str| review.get(name).and_then(Value::as_u64);
+                let default = Review::default();
+                reviews.insert(key.clone(), Review {
+                    due: number("due").unwrap_or(default.due),
+                    interval: number("interval").unwrap_or(default.interval),
+                    ease: number("ease").map_or(default.ease, |e| e as u32),
+                    reps: number("reps").map_or(default.reps, |r| r as u32),
+                    lapses: number("lapses").map_or(default.lapses, |l| l as u32),
+                });
+            }
+        }
+        Ok(Schedule { path: path.to_path_buf(), reviews })
+    }
+
+    // Write the schedule back to its sidecar file
+    pub fn save(&self) -> Result<(), failure::Error> {
+        let mut keys: Vec<&String> = self.reviews.keys().collect();
+        keys.sort();
+        let mut reviews = Map::new();
+        for key in keys {
+            let review = &self.reviews[key];
+            reviews.insert(key.clone(), json!({
+                "due": review.due,
+                "interval": review.interval,
+                "ease": review.ease,
+                "reps": review.reps,
+                "lapses": review.lapses,
+            }));
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&Value::Object(reviews))? + "\n")
+            .map_err(|e| failure::format_err!("couldn't write `{}`: {}", self.path.display(), e))
+    }
+
+    // How the card with this key has been studied. Cards that haven't been are due straight away
+    pub fn review(&self, key: &str) -> Review {
+        self.reviews.get(key).copied().unwrap_or_default()
+    }
+
+    pub fn answer(&mut self, key: &str, answer: Answer, today: u64) {
+        self.reviews.entry(key.to_string()).or_default().answer(answer, today);
+    }
+}
+
+// The number of days since the Unix epoch
+pub fn today() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 86_400)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The intervals after answering a new card with each answer in turn
+    fn intervals(answers: &[Answer]) -> Vec<u64> {
+        let mut review = Review::default();
+        answers.iter().map(|&answer| {
+            review.answer(answer, 100);
+            assert_eq!(review.due, 100 + review.interval);
+            review.interval
+        }).collect()
+    }
+
+    #[test]
+    fn good_multiplies_by_ease() {
+        use Answer::Good;
+        assert_eq!(intervals(&[Good, Good, Good, Good, Good]), [1, 3, 8, 20, 50]);
+    }
+
+    #[test]
+    fn easy_grows_faster_and_raises_ease() {
+        let mut review = Review { interval: 10, reps: 3, ..Review::default() };
+        review.answer(Answer::Easy, 0);
+        // 10 days * 2.5 * 1.3, using the ease from before the answer
+        assert_eq!(review.interval, 33);
+        assert_eq!(review.ease, 2650);
+        assert_eq!(intervals(&[Answer::Easy]), [4]);
+    }
+
+    #[test]
+    fn hard_lowers_ease() {
+        let mut review = Review { interval: 10, reps: 3, ..Review::default() };
+        review.answer(Answer::Hard, 0);
+        assert_eq!(review.interval, 12);
+        assert_eq!(review.ease, 2350);
+        // A new card is still seen again tomorrow
+        assert_eq!(intervals(&[Answer::Hard]), [1]);
+    }
+
+    #[test]
+    fn again_is_a_lapse_once_learnt() {
+        let mut review = Review { interval: 20, reps: 4, ..Review::default() };
+        review.answer(Answer::Again, 50);
+        assert_eq!((review.interval, review.due, review.reps, review.lapses), (0, 50, 0, 1));
+        assert_eq!(review.ease, 2300);
+        // Forgetting a card that was never remembered isn't a lapse
+        review.answer(Answer::Again, 50);
+        assert_eq!(review.lapses, 1);
+        // Learning it again starts from the beginning
+        review.answer(Answer::Good, 50);
+        assert_eq!(review.interval, 1);
+    }
+
+    #[test]
+    fn ease_has_a_minimum_and_intervals_always_grow() {
+        let mut review = Review { interval: 1, reps: 3, ease: 1400, ..Review::default() };
+        review.answer(Answer::Again, 0);
+        assert_eq!(review.ease, MINIMUM_EASE);
+        review = Review { interval: 1, reps: 3, ease: MINIMUM_EASE, ..Review::default() };
+        review.answer(Answer::Good, 0);
+        // 1 day * 1.3 rounds back down to 1, so it is pushed on a day
+        assert_eq!(review.interval, 2);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("md-to-anki-test-{}.study.json", std::process::id()));
+        let mut schedule = Schedule::load(&path).unwrap();
+        assert_eq!(schedule.review("abc").ease, STARTING_EASE);
+        schedule.answer("abc", Answer::Good, 10);
+        schedule.answer("abc", Answer::Good, 11);
+        schedule.save().unwrap();
+        let review = Schedule::load(&path).unwrap().review("abc");
+        fs::remove_file(&path).unwrap();
+        assert_eq!((review.due, review.interval, review.reps), (14, 3, 2));
+    }
+}
@@ -0,0 +1,129 @@
+//! Searching the cards in `edit`. A query is made of words that must all be found in the front,
+//! back or tags of a card, ignoring case, and `tag:` and `deck:` words that only look at those
+use crate::card::Card;
+use std::ops::Range;
+
+/// A search for cards, read from what was typed after `/`
+#[derive(Default)]
+pub struct Query {
+    /// Words looked for anywhere on the card
+    pub words: Vec<String>,
+    /// Tags the card must have, as a whole tag or a parent of one, like `bio` for `bio::cells`
+    pub tags: Vec<String>,
+    /// Text the name of the deck of the card must contain
+    pub decks: Vec<String>,
+}
+impl Query {
+    pub fn parse(text: &str) -> Query {
+        let mut query = Query::default();
+        for word in text.split_whitespace() {
+            if let Some(tag) = word.strip_prefix("tag:") {
+                query.tags.push(tag.to_string());
+            } else if let Some(deck) = word.strip_prefix("deck:") {
+                query.decks.push(deck.to_string());
+            } else {
+                query.words.push(word.to_string());
+            }
+        }
+        query
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.tags.is_empty() && self.decks.is_empty()
+    }
+
+    pub fn matches(&self, card: &Card) -> bool {
+        let deck = card.deck.as_deref().unwrap_or("");
+        let tags = card.tag_text();
+        self.words.iter().all(|word| [&card.front, &card.back, &tags].iter().any(|field| find(field, word).is_some()))
+            && self.tags.iter().all(|tag| card.tags.iter().any(|t| is_tag(t, tag)))
+            && self.decks.iter().all(|name| find(deck, name).is_some())
+    }
+}
+
+// Whether a tag is the one searched for, or is inside it, ignoring case
+fn is_tag(tag: &str, searched: &str) -> bool {
+    let tag = tag.to_lowercase();
+    let searched = searched.to_lowercase();
+    tag == searched || tag.starts_with(&(searched + "::"))
+}
+
+// Find the first place a word is in some text, ignoring case
+pub fn find(text: &str, word: &str) -> Option<Range<usize>> {
+    find_all(text, word).into_iter().next()
+}
+
+// Find every place a word is in some text, ignoring case. The ranges don't overlap
+pub fn find_all(text: &str, word: &str) -> Vec<Range<usize>> {
+    let word: Vec<char> = word.chars().flat_map(char::to_lowercase).collect();
+    let mut found = Vec::new();
+    if word.is_empty() {
+        return found;
+    }
+    let mut after = 0;
+    for (start, _) in text.char_indices() {
+        if start < after {
+            continue;
+        }
+        // Compare lowercased characters, keeping track of how much of the text was used
+        let mut end = start;
+        let mut wanted = word.iter();
+        let mut matched = true;
+        'chars: for c in text[start..].chars() {
+            for lower in c.to_lowercase() {
+                match wanted.next() {
+                    Some(&w) if w == lower => {},
+                    _ => {
+                        matched = false;
+                        break 'chars;
+                    },
+                }
+            }
+            end += c.len_utf8();
+            if wanted.len() == 0 {
+                break;
+            }
+        }
+        if matched && wanted.len() == 0 {
+            found.push(start..end);
+            after = end;
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_words_ignoring_case() {
+        assert_eq!(find_all("Hello hello HELLO", "hello"), [0..5, 6..11, 12..17]);
+        assert_eq!(find_all("ÉCOLE and école", "École"), [0..6, 11..17]);
+        assert_eq!(find("Mitochondria", "CHON"), Some(4..8));
+        assert_eq!(find("abc", "abcd"), None);
+        assert!(find_all("abc", "").is_empty());
+    }
+
+    #[test]
+    fn matches_dont_overlap() {
+        assert_eq!(find_all("aaaa", "aa"), [0..2, 2..4]);
+        assert_eq!(find_all("aaa", "aa"), vec![Range { start: 0, end: 2 }]);
+        assert_eq!(find_all("ababa", "aba"), vec![Range { start: 0, end: 3 }]);
+    }
+
+    #[test]
+    fn queries() {
+        let mut card = Card::new("What is osmosis?".to_string(), "Diffusion of water<br>".to_string(), vec!["Bio::Cells".to_string()]);
+        card.deck = Some("Biology::Week_1".to_string());
+        let matches = |text: &str| Query::parse(text).matches(&card);
+        assert!(matches("OSMOSIS water"));
+        assert!(matches("cells"));
+        assert!(!matches("osmosis salt"));
+        assert!(matches("tag:bio tag:Bio::Cells deck:week"));
+        assert!(!matches("tag:Cells"));
+        assert!(!matches("tag:Bi"));
+        assert!(!matches("deck:chemistry"));
+        assert!(Query::parse("  ").is_empty());
+    }
+}
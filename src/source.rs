@@ -0,0 +1,476 @@
+//! The markdown documents cards are read from, and changes written back into them
+use crate::card::{self, Card, GuidSource, Line, Syntax};
+use crate::cli::UsageError;
+use crate::parse::{self, parse_md, ParseError, ID_MARKER_END, ID_MARKER_START};
+
+use std::{fs, io, ops::Range, path::{Path, PathBuf}};
+
+// The extensions of the files searched for in directories
+const EXTENSIONS: [&str; 2] = ["md", "markdown"];
+// Characters that mark an input as a glob pattern
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+/// A markdown file containing cards
+pub struct Document {
+    pub path: PathBuf,
+    /// The path relative to the directory or glob the document was found through
+    pub relative: PathBuf,
+    pub markdown: String,
+}
+impl Document {
+    pub fn load(path: &Path, relative: &Path) -> io::Result<Document> {
+        Ok(Document {
+            path: path.to_path_buf(),
+            relative: relative.to_path_buf(),
+            markdown: fs::read_to_string(path)?,
+        })
+    }
+    // The directories of the relative path followed by the file name without its extension,
+    // e.g. `["biology", "cells"]` for `biology/cells.md`
+    pub fn path_parts(&self) -> Vec<String> {
+        self.relative.with_extension("").iter()
+            .map(|part| part.to_string_lossy().to_string())
+            .collect()
+    }
+    // The name to show the user
+    pub fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+    // Parse the cards in the document, remembering which file they came from
+    pub fn parse(&self, options: &parse::Options) -> Result<Vec<Card>, Vec<ParseError>> {
+        let mut cards = parse_md(&self.markdown, options)?;
+        for card in cards.iter_mut() {
+            card.path = Some(self.path.clone());
+        }
+        Ok(cards)
+    }
+    // Write ids into the file for every card in it without one, returning the cards with their ids.
+    // Nothing is written unless the result parses into the same number of cards
+    pub fn write_ids(&mut self, cards: &[Card], source: GuidSource, options: &parse::Options)
+        -> Result<Vec<Card>, failure::Error> {
+        let markdown = insert_ids(&self.markdown, cards, source);
+        let mut parsed = parse_md(&markdown, options).map_err(|errors| {
+            failure::format_err!("not writing ids to `{}`: {}", self.name(), errors[0])
+        })?;
+        if parsed.len() != cards.len() {
+            failure::bail!("not writing ids to `{}`: the cards would change", self.name());
+        }
+        fs::write(&self.path, &markdown)
+            .map_err(|e| failure::format_err!("couldn't write `{}`: {}", self.name(), e))?;
+        self.markdown = markdown;
+        for card in parsed.iter_mut() {
+            card.path = Some(self.path.clone());
+        }
+        Ok(parsed)
+    }
+    // Write the cards from this document that were edited, added or `deleted` back into it.
+    // Tags left without a list are removed too. Nothing is written if the result wouldn't
+    // parse. Afterwards the spans of the cards are moved to where they now are, and the deleted
+    // cards are forgotten. Returns whether anything changed
+    pub fn save(&mut self, cards: &mut [Card], deleted: &mut Vec<Card>, options: &parse::Options)
+        -> Result<bool, failure::Error> {
+        let ours = |card: &&Card| card.path.as_ref() == Some(&self.path) && card.span.is_some();
+        let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+        for card in cards.iter().filter(ours).filter(|c| c.edited) {
+            let span = card.span.clone().unwrap_or_default();
+            let after = |c: &&Card| c.span.as_ref().is_some_and(|s| s.end == span.start && !s.is_empty());
+            if !span.is_empty() {
+                // The blank lines and line break that ended the card are kept
+                let end = span.start + self.markdown[span.clone()].trim_end().len();
+                edits.push((span.start..end, to_markdown(&self.markdown, card, span.start)));
+            } else if let Some(sibling) = cards.iter().filter(ours).find(after) {
+                // A new card goes on the line after the card it was added after, laid out like it
+                let start = sibling.span.clone().unwrap_or_default().start;
+                let end = start + self.markdown[start..span.start].trim_end().len();
+                let indent = line_prefix(&self.markdown, start);
+                edits.push((end..end, format!("\n{}{}", indent, to_markdown(&self.markdown, card, start))));
+            } else {
+                // The card it was added after has been deleted, so it goes where that card ended
+                let start = deleted.iter().filter(ours).find(after)
+                    .and_then(|c| c.span.as_ref())
+                    .map_or(span.start, |s| s.start);
+                let text = to_markdown(&self.markdown, card, start);
+                let indent = line_prefix(&self.markdown, start);
+                if span.start == 0 || self.markdown[..span.start].ends_with('\n') {
+                    edits.push((span, format!("{}{}\n", indent, text)));
+                } else {
+                    edits.push((span, format!("\n{}{}", indent, text)));
+                }
+            }
+        }
+        for card in deleted.iter().filter(ours) {
+            let span = card.span.clone().unwrap_or_default();
+            if !span.is_empty() {
+                edits.push((whole_lines(&self.markdown, span), String::new()));
+            }
+        }
+        if edits.is_empty() {
+            return Ok(false);
+        }
+        edits.sort_by_key(|(range, _)| (range.start, range.end));
+
+        let mut markdown = String::new();
+        let mut last = 0;
+        for (range, text) in &edits {
+            markdown += &self.markdown[last..range.start.max(last)];
+            markdown += text;
+            last = last.max(range.end);
+        }
+        markdown += &self.markdown[last..];
+
+        // Deleting every card beneath a tag leaves the tag without a list, so remove it as well
+        let parsed = loop {
+            match parse_md(&markdown, options) {
+                Ok(parsed) => break parsed,
+                Err(errors) => {
+                    let missing: Vec<usize> = errors.iter()
+                        .filter_map(|e| match e {
+                            ParseError::MissingList(p) => Some(p.line),
+                            _ => None,
+                        })
+                        .collect();
+                    if missing.len() < errors.len() || missing.is_empty() {
+                        let error = errors.iter().find(|e| !matches!(e, ParseError::MissingList(_)))
+                            .unwrap_or(&errors[0]);
+                        failure::bail!("not saving `{}`: {}", self.name(), error);
+                    }
+                    // The blank line after each tag goes with it
+                    let lines: Vec<&str> = markdown.split_inclusive('\n').collect();
+                    markdown = lines.iter().enumerate()
+                        .filter(|(i, line)| {
+                            let after_tag = *i > 0 && missing.contains(&(i - 1));
+                            let blank_after_tag = after_tag && line.trim().is_empty();
+                            !missing.contains(i) && !blank_after_tag
+                        })
+                        .map(|(_, line)| *line)
+                        .collect();
+                },
+            }
+        };
+        fs::write(&self.path, &markdown)
+            .map_err(|e| failure::format_err!("couldn't write `{}`: {}", self.name(), e))?;
+        self.markdown = markdown;
+        deleted.retain(|card| card.path.as_ref() != Some(&self.path));
+
+        // The cards are still in the same order as in the document, with new cards before
+        // whichever card came after the one they were added after
+        let mut saved: Vec<&mut Card> = cards.iter_mut()
+            .filter(|card| card.path.as_ref() == Some(&self.path) && card.span.is_some())
+            .collect();
+        saved.sort_by_key(|card| {
+            let span = card.span.clone().unwrap_or_default();
+            (span.start, !span.is_empty())
+        });
+        if saved.len() != parsed.len() {
+            // Don't write to spans that might be wrong
+            for card in saved {
+                card.span = None;
+                card.edited = false;
+            }
+            failure::bail!("saved `{}`, but reopen it to save it again", self.name());
+        }
+        for (card, parsed) in saved.into_iter().zip(parsed) {
+            card.span = parsed.span;
+            card.edited = false;
+        }
+        Ok(true)
+    }
+}
+
+// Find every markdown document an input refers to, along with its path relative to the input.
+// Directories are searched recursively and glob patterns are expanded
+pub fn find_documents(input: &str) -> Result<Vec<(PathBuf, PathBuf)>, failure::Error> {
+    let path = Path::new(input);
+    if path.is_dir() {
+        let mut found = Vec::new();
+        search_directory(path, &mut found)?;
+        found.sort();
+        return Ok(found.into_iter()
+            .map(|p| {
+                let relative = p.strip_prefix(path).unwrap_or(&p).to_path_buf();
+                (p, relative)
+            })
+            .collect());
+    }
+    if input.contains(GLOB_CHARS) {
+        // Paths are relative to the directories before the first part with a pattern in it
+        let root = input_root(input);
+        let mut found = Vec::new();
+        let entries = glob::glob(input)
+            .map_err(|e| UsageError(format!("`{}` isn't a valid glob pattern: {}", input, e)))?;
+        for entry in entries {
+            let p = entry?;
+            if p.is_file() {
+                let relative = p.strip_prefix(&root).unwrap_or(&p).to_path_buf();
+                found.push((p, relative));
+            }
+        }
+        if found.is_empty() {
+            return Err(UsageError(format!("no files match `{}`", input)).into());
+        }
+        return Ok(found);
+    }
+    let relative = path.file_name().map_or_else(|| path.to_path_buf(), PathBuf::from);
+    Ok(vec![(path.to_path_buf(), relative)])
+}
+
+// The part of an input before any glob pattern, e.g. `notes/bio` for `notes/bio/*.md`
+pub fn input_root(input: &str) -> PathBuf {
+    Path::new(input).iter()
+        .take_while(|part| !part.to_string_lossy().contains(GLOB_CHARS))
+        .collect()
+}
+
+// The file named after an input with another extension, e.g. `notes/bio.txt` for `notes/bio.md`
+// or `'notes/bio/*.md'`. Returns `None` if there is no name to use, e.g. for `'*.md'`
+pub fn default_path(input: &str, extension: &str) -> Option<PathBuf> {
+    let root = input_root(input);
+    root.file_name()?;
+    Some(root.with_extension(extension))
+}
+
+fn search_directory(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            search_directory(&path, found)?;
+        } else if path.extension().is_some_and(|e| EXTENSIONS.iter().any(|x| e == *x)) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Write a card out as markdown, the same way it was first written. `start` is where the card
+// or the card it is laid out like starts, which is used to match its list marker and indentation
+fn to_markdown(markdown: &str, card: &Card, start: usize) -> String {
+    let id = card.id.as_ref()
+        .map(|id| format!(" {} {} {}", ID_MARKER_START, id, ID_MARKER_END))
+        .unwrap_or_default();
+    let bullet: String = markdown[start..].chars().take_while(|c| !c.is_whitespace()).collect();
+    let (first, rest) = match card.syntax.clone().unwrap_or(Syntax::Item) {
+        Syntax::Inline { separator } => {
+            let back: Vec<String> = card.back_lines().into_iter().map(line_text).collect();
+            let back = back.join("<br>");
+            return format!("{} {} {}{}", card.front, separator, back, id);
+        },
+        Syntax::Item => (card.front.clone(), card.back_lines()),
+        Syntax::Definition { reversed } => {
+            let (term, mut definition) = if reversed {
+                let term: Vec<String> = card.back_lines().into_iter().map(line_text).collect();
+                (term.join(" "), card::field_lines(&card.front))
+            } else {
+                let term = card.front.strip_prefix("Define: ").unwrap_or(&card.front);
+                (term.to_string(), card.back_lines())
+            };
+            let first = match definition.first() {
+                Some(Line::Text(_)) => line_text(definition.remove(0)),
+                _ => String::new(),
+            };
+            (format!("**{}**: {}", term, first).trim_end().to_string(), definition)
+        },
+    };
+
+    let indent = line_prefix(markdown, start) + &" ".repeat(bullet.chars().count() + 1);
+    let nested = if ["-", "*", "+"].contains(&bullet.as_str()) { bullet.as_str() } else { "-" };
+    let mut text = format!("{} {}{}", bullet, first, id);
+    for line in rest {
+        match line {
+            Line::Text(line) => text += &format!("\n{}{} {}", indent, nested, line),
+            Line::Code { language, code } => {
+                let mut block = Card::new(String::new(), String::new(), Vec::new());
+                block.add_code_to_back(&language, &code);
+                for code_line in block.back.trim_matches('\n').lines() {
+                    text += format!("\n{}{}", indent, code_line).trim_end();
+                }
+            },
+        }
+    }
+    text
+}
+
+// The text of a line of a field, with code blocks written out as they are
+fn line_text(line: Line) -> String {
+    match line {
+        Line::Text(text) => text,
+        Line::Code { code, .. } => code.trim_end().to_string(),
+    }
+}
+
+// What comes before an offset on its line, such as the indentation of a list item. Anything
+// other than whitespace and blockquote markers is replaced by spaces
+fn line_prefix(markdown: &str, offset: usize) -> String {
+    let line_start = markdown[..offset].rfind('\n').map_or(0, |i| i + 1);
+    markdown[line_start..offset].chars()
+        .map(|c| if c == '>' || c.is_whitespace() { c } else { ' ' })
+        .collect()
+}
+
+// Extend a span to cover the whole lines it is on, including the line break that ends it, if
+// there is nothing else on them. Blank lines after the span are kept
+fn whole_lines(markdown: &str, span: Range<usize>) -> Range<usize> {
+    let span = span.start..span.start + markdown[span].trim_end().len();
+    let line_start = markdown[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let start = if markdown[line_start..span.start].trim().is_empty() { line_start } else { span.start };
+    let line_end = markdown[span.end..].find('\n').map_or(markdown.len(), |i| span.end + i + 1);
+    let end = if markdown[span.end..line_end].trim().is_empty() { line_end } else { span.end };
+    start..end
+}
+
+// Add an `<!-- id: ... -->` marker to the end of the first line of every card that doesn't
+// have one yet, so the card keeps the same GUID however it is edited afterwards
+pub fn insert_ids(markdown: &str, cards: &[Card], source: GuidSource) -> String {
+    let mut insertions: Vec<(usize, String)> = cards.iter()
+        .filter(|card| card.id.is_none())
+        .filter_map(|card| {
+            let span = card.span.as_ref()?;
+            let item = &markdown[span.clone()];
+            let line_end = item.find('\n').unwrap_or(item.len());
+            let offset = span.start + item[..line_end].trim_end().len();
+            Some((offset, format!(" {} {} {}", ID_MARKER_START, card.guid(source), ID_MARKER_END)))
+        })
+        .collect();
+    insertions.sort_by_key(|(offset, _)| *offset);
+
+    let mut output = String::new();
+    let mut last = 0;
+    for (offset, marker) in insertions {
+        output += &markdown[last..offset];
+        output += &marker;
+        last = offset;
+    }
+    output + &markdown[last..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A document in a temporary file, along with its cards
+    fn document(name: &str, markdown: &str) -> (Document, Vec<Card>) {
+        let path = std::env::temp_dir().join(format!("md-to-anki-test-{}-{}.md", std::process::id(), name));
+        let document = Document { path: path.clone(), relative: PathBuf::from("test.md"), markdown: markdown.to_string() };
+        let cards = document.parse(&parse::Options::default()).unwrap_or_else(|errors| panic!("{}", errors[0]));
+        (document, cards)
+    }
+
+    // Save the document, returning what was written to the file
+    fn save(document: &mut Document, cards: &mut [Card], deleted: &mut Vec<Card>) -> String {
+        document.save(cards, deleted, &parse::Options::default()).unwrap();
+        let written = fs::read_to_string(&document.path).unwrap();
+        fs::remove_file(&document.path).unwrap();
+        assert_eq!(written, document.markdown);
+        written
+    }
+
+    // A card added after another one, the way the editor does
+    fn new_card(after: &Card, front: &str, back: &str) -> Card {
+        let mut card = Card::new(front.to_string(), String::new(), after.tags.clone());
+        card.add_to_back(back);
+        card.path = after.path.clone();
+        card.span = after.span.as_ref().map(|span| span.end..span.end);
+        card.syntax = after.syntax.clone();
+        card.edited = true;
+        card
+    }
+
+    const MARKDOWN: &str = "# Cells\n\n[](question)\n- One\n  - 1\n- Two\n  - 2\n\nProse.\n\n[](question)\n- Three\n  - 3\n";
+
+    #[test]
+    fn save_edits() {
+        let (mut document, mut cards) = document("edit", MARKDOWN);
+        cards[1].front = "Zwei".to_string();
+        cards[1].back = "2<br>zwei<br>".to_string();
+        cards[1].edited = true;
+        let written = save(&mut document, &mut cards, &mut Vec::new());
+        assert_eq!(written, MARKDOWN.replace("- Two\n  - 2\n", "- Zwei\n  - 2\n  - zwei\n"));
+        // The spans now point at the new text, so saving again still works
+        assert!(!cards[1].edited);
+        assert!(written[cards[1].span.clone().unwrap()].starts_with("- Zwei"));
+        cards[2].front = "Drei".to_string();
+        cards[2].edited = true;
+        let written = save(&mut document, &mut cards, &mut Vec::new());
+        assert!(written.ends_with("- Drei\n  - 3\n"));
+    }
+
+    #[test]
+    fn save_new_cards() {
+        let (mut document, mut cards) = document("new", MARKDOWN);
+        let card = new_card(&cards[0], "Half", "1.5");
+        cards.insert(1, card);
+        let card = new_card(&cards[3], "Four", "4");
+        cards.push(card);
+        let written = save(&mut document, &mut cards, &mut Vec::new());
+        assert_eq!(written, MARKDOWN
+            .replace("- One\n  - 1\n", "- One\n  - 1\n- Half\n  - 1.5\n")
+            .replace("- Three\n  - 3\n", "- Three\n  - 3\n- Four\n  - 4\n"));
+        assert_eq!(cards.iter().filter(|c| c.edited).count(), 0);
+    }
+
+    #[test]
+    fn save_deletions() {
+        let (mut document, mut cards) = document("delete", MARKDOWN);
+        let mut deleted = vec![cards.remove(0)];
+        let written = save(&mut document, &mut cards, &mut deleted);
+        assert_eq!(written, MARKDOWN.replace("- One\n  - 1\n", ""));
+        assert!(deleted.is_empty());
+    }
+
+    #[test]
+    fn deleting_every_card_removes_the_tag() {
+        let (mut document, mut cards) = document("tag", MARKDOWN);
+        let mut deleted = vec![cards.remove(2)];
+        let written = save(&mut document, &mut cards, &mut deleted);
+        assert_eq!(written, "# Cells\n\n[](question)\n- One\n  - 1\n- Two\n  - 2\n\nProse.\n\n");
+        assert_eq!(cards.len(), 2);
+    }
+
+    #[test]
+    fn new_card_after_a_deleted_one() {
+        let (mut document, mut cards) = document("replace", MARKDOWN);
+        let card = new_card(&cards[2], "Replacement", "r");
+        let mut deleted = vec![cards.remove(2)];
+        cards.push(card);
+        let written = save(&mut document, &mut cards, &mut deleted);
+        assert!(written.ends_with("Prose.\n\n[](question)\n- Replacement\n  - r\n"), "{}", written);
+    }
+
+    #[test]
+    fn nothing_is_written_if_it_would_not_parse() {
+        let markdown = "[](cloze)\n- The capital of France is ==Paris==\n";
+        let (mut document, mut cards) = document("invalid", markdown);
+        cards[0].front = "No deletions".to_string();
+        cards[0].edited = true;
+        assert!(document.save(&mut cards, &mut Vec::new(), &parse::Options::default()).is_err());
+        assert!(!document.path.exists());
+        assert_eq!(document.markdown, markdown);
+    }
+
+    #[test]
+    fn inline_cards_keep_spaces_around_the_separator() {
+        let (mut document, mut cards) = document("inline", "Intro.\n\nWhat is it? :: Answer\n");
+        cards[0].front = "Bio".to_string();
+        cards[0].back = "Cells<br>".to_string();
+        cards[0].edited = true;
+        let written = save(&mut document, &mut cards, &mut Vec::new());
+        assert_eq!(written, "Intro.\n\nBio :: Cells\n");
+    }
+
+    #[test]
+    fn ids_go_at_the_end_of_the_first_line() {
+        let markdown = "[](question)\n- Größe? <!-- id: kept -->\n  - groß\n- 大きい？  \n  - big\n\nInline? :: Yes\n";
+        let (_, cards) = document("ids", markdown);
+        let with_ids = insert_ids(markdown, &cards, GuidSource::Front);
+        let guid = |i: usize| cards[i].guid(GuidSource::Front);
+        assert_eq!(with_ids, format!(
+            "[](question)\n- Größe? <!-- id: kept -->\n  - groß\n- 大きい？ <!-- id: {} -->  \n  - big\n\nInline? :: Yes <!-- id: {} -->\n",
+            guid(1), guid(2),
+        ));
+        let parsed = parse_md(&with_ids, &parse::Options::default()).unwrap_or_else(|errors| panic!("{}", errors[0]));
+        let ids: Vec<String> = parsed.iter().filter_map(|card| card.id.clone()).collect();
+        assert_eq!(ids, ["kept".to_string(), guid(1), guid(2)]);
+        assert_eq!(parsed[1].front, "大きい？");
+        // Cards with ids are left alone
+        assert_eq!(insert_ids(&with_ids, &parsed, GuidSource::Front), with_ids);
+    }
+}
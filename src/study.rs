@@ -0,0 +1,99 @@
+//! Studying the cards in the terminal, to check them or drill them before they are imported
+use crate::card::{self, Card, GuidSource, NoteType};
+use crate::schedule::{self, Answer, Schedule};
+
+use std::collections::VecDeque;
+
+/// One side of a card that is studied on its own, like each card of an Anki note
+struct Item {
+    card: usize,
+    /// Which side of a reversed card, or which deletion of a cloze card, is asked
+    ord: usize,
+    /// What the item is called in the schedule
+    key: String,
+}
+
+/// A study session, asking every item that is due in turn
+pub struct Study {
+    cards: Vec<Card>,
+    queue: VecDeque<Item>,
+    schedule: Schedule,
+    today: u64,
+    /// Whether the answer to the current item is being shown
+    pub revealed: bool,
+    /// How many answers have been given this session
+    pub reviewed: usize,
+}
+impl Study {
+    // Start a session with the items that are due, or with every item if `all` is set
+    pub fn new(cards: Vec<Card>, schedule: Schedule, source: GuidSource, all: bool) -> Study {
+        let today = schedule::today();
+        let mut queue = VecDeque::new();
+        for (i, card) in cards.iter().enumerate() {
+            let guid = card.guid(source);
+            let ords = match card.note_type {
+                NoteType::Basic => vec![0],
+                NoteType::BasicAndReversed => vec![0, 1],
+                NoteType::Cloze => card.cloze_numbers(),
+            };
+            for ord in ords {
+                let key = format!("{}:{}", guid, ord);
+                if all || schedule.review(&key).due <= today {
+                    queue.push_back(Item { card: i, ord, key });
+                }
+            }
+        }
+        Study { cards, queue, schedule, today, revealed: false, reviewed: 0 }
+    }
+
+    // The number of items left to study this session
+    pub fn remaining(&self) -> usize {
+        self.queue.len()
+    }
+
+    // The text for the Front pane. Cloze deletions are filled in once the answer is shown
+    pub fn front(&self) -> String {
+        let item = match self.queue.front() {
+            Some(item) => item,
+            None => return String::new(),
+        };
+        let card = &self.cards[item.card];
+        match (card.note_type, item.ord) {
+            (NoteType::Cloze, n) => card::cloze_text(&card.front, n, self.revealed),
+            (_, 1) => card.back.clone(),
+            _ => card.front.clone(),
+        }
+    }
+
+    // The text for the Back pane, which is empty until the answer is shown
+    pub fn back(&self) -> String {
+        let item = match self.queue.front() {
+            Some(item) if self.revealed => item,
+            _ => return String::new(),
+        };
+        let card = &self.cards[item.card];
+        match (card.note_type, item.ord) {
+            (NoteType::BasicAndReversed, 1) => card.front.clone(),
+            _ => card.back.clone(),
+        }
+    }
+
+    pub fn reveal(&mut self) {
+        self.revealed = true;
+    }
+
+    // Schedule the current item and move on to the next one. Forgotten items are asked again
+    // at the end of the session. The schedule is saved straight away so no answers are lost
+    pub fn respond(&mut self, answer: Answer) -> Result<(), failure::Error> {
+        if let Some(item) = self.queue.pop_front() {
+            self.schedule.answer(&item.key, answer, self.today);
+            self.schedule.save()?;
+            self.reviewed += 1;
+            if answer == Answer::Again {
+                self.queue.push_back(item);
+            }
+        }
+        self.revealed = false;
+        Ok(())
+    }
+}
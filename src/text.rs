@@ -0,0 +1,153 @@
+//! Writing cards into a delimited text file that Anki can import. The file starts with header
+//! lines telling Anki how to read it, so nothing has to be set in the import dialog
+use crate::card::{Card, NoteType};
+
+// The tag given to suspended cards
+const SUSPENDED_TAG: &str = "suspended";
+
+/// The character separating the fields of each card
+#[derive(PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum Delimiter {
+    Tab,
+    Semicolon,
+    Comma,
+}
+impl Delimiter {
+    fn char(self) -> char {
+        match self {
+            Delimiter::Tab => '\t',
+            Delimiter::Semicolon => ';',
+            Delimiter::Comma => ',',
+        }
+    }
+    // The name Anki uses in the `#separator:` header
+    fn name(self) -> &'static str {
+        match self {
+            Delimiter::Tab => "Tab",
+            Delimiter::Semicolon => "Semicolon",
+            Delimiter::Comma => "Comma",
+        }
+    }
+}
+
+// Create the contents of a text file Anki can import. The deck is only written to the file if
+// it was chosen explicitly or some cards are in subdecks, otherwise it is picked when importing
+pub fn write(cards: &[Card], delimiter: Delimiter, deck: &str, deck_chosen: bool) -> String {
+    let mut output = format!("#separator:{}\n#html:true\n", delimiter.name());
+
+    // Columns are only added for the note type and deck if they differ between cards
+    let mut columns = vec!["guid"];
+    let note_types: Vec<NoteType> = cards.iter().map(|c| c.note_type).collect();
+    if note_types.iter().any(|t| *t != note_types[0]) {
+        columns.push("notetype");
+    } else {
+        let note_type = note_types.first().copied().unwrap_or(NoteType::Basic);
+        output += &format!("#notetype:{}\n", note_type.name());
+    }
+    if cards.iter().any(|c| c.deck.is_some()) {
+        columns.push("deck");
+    } else if deck_chosen {
+        output += &format!("#deck:{}\n", deck);
+    }
+    columns.push("front");
+    columns.push("back");
+    columns.push("tags");
+    for (i, column) in columns.iter().enumerate() {
+        // The GUID column lets importing again update the existing notes
+        if ["guid", "notetype", "deck", "tags"].contains(column) {
+            output += &format!("#{} column:{}\n", column, i + 1);
+        }
+    }
+
+    for card in cards {
+        // Text files can't suspend cards, so suspended ones are tagged to be found in Anki instead
+        let mut tags = card.tags.clone();
+        if card.suspended && !tags.iter().any(|tag| tag == SUSPENDED_TAG) {
+            tags.push(SUSPENDED_TAG.to_string());
+        }
+        let tags = tags.join(" ");
+        let fields: Vec<&str> = columns.iter().map(|column| match *column {
+            "guid" => card.id.as_deref().unwrap_or_default(),
+            "notetype" => card.note_type.name(),
+            "deck" => card.deck.as_deref().unwrap_or(deck),
+            "front" => &card.front,
+            "back" => &card.back,
+            _ => &tags,
+        }).collect();
+        let row: Vec<String> = fields.iter().map(|f| quote(f, delimiter)).collect();
+        output += &row.join(&delimiter.char().to_string());
+        output += "\n";
+    }
+    output
+}
+
+// Quote a field as described in RFC 4180 if it contains the delimiter, a quote or a line break
+fn quote(field: &str, delimiter: Delimiter) -> String {
+    if field.contains(|c| c == delimiter.char() || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(front: &str, back: &str) -> Card {
+        let mut card = Card::new(front.to_string(), back.to_string(), vec!["Bio::Cells".to_string()]);
+        card.id = Some("abc".to_string());
+        card
+    }
+
+    #[test]
+    fn quotes_fields_that_need_it() {
+        assert_eq!(quote("plain", Delimiter::Semicolon), "plain");
+        assert_eq!(quote("a;b", Delimiter::Semicolon), "\"a;b\"");
+        assert_eq!(quote("a;b", Delimiter::Tab), "a;b");
+        assert_eq!(quote("a\tb", Delimiter::Tab), "\"a\tb\"");
+        assert_eq!(quote("a,b", Delimiter::Comma), "\"a,b\"");
+        assert_eq!(quote("say \"hi\"", Delimiter::Tab), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote("one\ntwo", Delimiter::Tab), "\"one\ntwo\"");
+        assert_eq!(quote("one\r\ntwo", Delimiter::Tab), "\"one\r\ntwo\"");
+    }
+
+    #[test]
+    fn header_and_rows() {
+        let cards = [card("What; why?", "Because \"so\""), card("Code", "```\nfn main() {}\n```")];
+        assert_eq!(write(&cards, Delimiter::Semicolon, "Deck", false), concat!(
+            "#separator:Semicolon\n#html:true\n#notetype:Basic\n#guid column:1\n#tags column:4\n",
+            "abc;\"What; why?\";\"Because \"\"so\"\"\";Bio::Cells\n",
+            "abc;Code;\"```\nfn main() {}\n```\";Bio::Cells\n",
+        ));
+    }
+
+    #[test]
+    fn chosen_deck_goes_in_the_header() {
+        let output = write(&[card("Q", "A")], Delimiter::Tab, "Biology", true);
+        assert_eq!(output, "#separator:Tab\n#html:true\n#notetype:Basic\n#deck:Biology\n#guid column:1\n#tags column:4\nabc\tQ\tA\tBio::Cells\n");
+    }
+
+    #[test]
+    fn columns_for_mixed_note_types_and_subdecks() {
+        let mut cloze = card("{{c1::Paris}}", "");
+        cloze.note_type = NoteType::Cloze;
+        cloze.deck = Some("Biology::Cells".to_string());
+        let output = write(&[card("Q", "A"), cloze], Delimiter::Tab, "Biology", false);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[2..6], ["#guid column:1", "#notetype column:2", "#deck column:3", "#tags column:6"]);
+        assert_eq!(lines[6], "abc\tBasic\tBiology\tQ\tA\tBio::Cells");
+        assert_eq!(lines[7], "abc\tCloze\tBiology::Cells\t{{c1::Paris}}\t\tBio::Cells");
+    }
+
+    #[test]
+    fn suspended_cards_are_tagged() {
+        let mut suspended = card("Q", "A");
+        suspended.suspended = true;
+        let output = write(&[suspended.clone()], Delimiter::Tab, "Deck", false);
+        assert!(output.ends_with("\tBio::Cells suspended\n"));
+        // Cards already tagged `suspended` don't get it twice
+        suspended.tags.push("suspended".to_string());
+        assert_eq!(write(&[suspended], Delimiter::Tab, "Deck", false), output);
+    }
+}
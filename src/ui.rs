@@ -4,11 +4,17 @@ use termion::input::MouseTerminal;
 use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
 use tui::backend::TermionBackend;
-use tui::layout::{Constraint, Direction, Layout, Alignment};
+use tui::layout::{Constraint, Direction, Layout, Alignment, Rect};
 use tui::style::{Color, Modifier, Style};
 use tui::widgets::{Block, Borders, SelectableList, Widget, Paragraph, Text};
 use tui::Terminal;
-use crate::app::App;
+use crate::app::{App, Prompt};
+use crate::card::{field_lines, field_text, Card, Line};
+use crate::editor::wrapped;
+use crate::parse::Attribute;
+use crate::search::find_all;
+use unicode_width::UnicodeWidthStr;
+use crate::study::Study;
 
 pub struct Ui {
     terminal: tui::terminal::Terminal<TermionBackend<AlternateScreen<MouseTerminal<RawTerminal<std::io::Stdout>>>>>,
@@ -26,6 +32,14 @@ impl Ui {
         Ok(Ui { terminal })
     }
 
+    // Give the terminal back while another program, like an editor, runs in it. Dropping the
+    // terminal leaves raw mode and the alternate screen, and they are set up again afterwards
+    pub fn suspend<T>(self, run: impl FnOnce() -> T) -> Result<(Ui, T), io::Error> {
+        drop(self);
+        let result = run();
+        Ok((Ui::new()?, result))
+    }
+
     // Render the UI
     pub fn render(&mut self, app: &App) -> Result<(), io::Error> {
 
@@ -41,10 +55,23 @@ impl Ui {
                 .constraints([Constraint::Percentage(20), Constraint::Percentage(80)].as_ref())
                 .split(f.size());
 
-            // heading
-            Paragraph::new(app.get_formatted_text().iter())
+            // heading, which is where searches and the answers to prompts are typed
+            let question = app.prompt.as_ref().map(Prompt::question);
+            let heading = if let Some(question) = &question {
+                let mut answer = vec![Text::raw(question.as_str())];
+                answer.extend(app.answer.render(inner_width(chunks[0]).saturating_sub(question.width()), 1));
+                answer
+            } else if app.is_searching {
+                let mut search = vec![Text::raw("/")];
+                search.extend(app.search.render(inner_width(chunks[0]).saturating_sub(1), 1));
+                search
+            } else {
+                vec![Text::raw(&app.text)]
+            };
+            let typing = app.is_searching || question.is_some();
+            Paragraph::new(heading.iter())
             .block(Block::default().title(app.title).borders(Borders::ALL))
-            .alignment(Alignment::Center)
+            .alignment(if typing { Alignment::Left } else { Alignment::Center })
             .wrap(true)
             .render(&mut f, chunks[0]);
 
@@ -56,20 +83,47 @@ impl Ui {
                     .split(chunks[1]);
 
                 // List of cards
-                let items: Vec<String> = app.cards.iter().map(|c| c.front.clone()).collect();
+                let visible = app.visible();
+                let range = app.range();
+                let items: Vec<String> = visible.iter()
+                    .map(|&i| {
+                        let card = &app.cards[i];
+                        let mark = if card.marked || range.contains(&i) { "* " } else { "" };
+                        let suspended = if card.suspended { " (suspended)" } else { "" };
+                        format!("{}{}{}", mark, card.front, suspended)
+                    })
+                    .collect();
+                let title = if app.filtered {
+                    format!("Cards ({} of {} match)", visible.len(), app.cards.len())
+                } else {
+                    "Cards".to_string()
+                };
                 SelectableList::default()
-                    .block(Block::default().borders(Borders::ALL).title("Cards"))
-                    .select(app.selected)
+                    .block(Block::default().borders(Borders::ALL).title(&title))
+                    .select(app.selected.and_then(|s| visible.iter().position(|&i| i == s)))
                     .items(&items[..])
                     .highlight_style(app.highlight_style)
                     .highlight_symbol(">>")
                     .render(&mut f, chunks[0]);
 
-                {
+                // Tag panel, in place of the selected card
+                if app.tag_panel {
+                    let tags: Vec<String> = app.tags().into_iter()
+                        .map(|(tag, count)| format!("{:>5} {}", count, tag))
+                        .collect();
+                    let marked = app.cards.iter().filter(|card| card.marked).count();
+                    SelectableList::default()
+                        .block(Block::default().borders(Borders::ALL).title(&format!("Tags ({} cards marked)", marked)))
+                        .select(if tags.is_empty() { None } else { Some(app.tag_selected.min(tags.len() - 1)) })
+                        .items(&tags[..])
+                        .highlight_style(app.highlight_style)
+                        .highlight_symbol(">>")
+                        .render(&mut f, chunks[1]);
+                } else {
                     let chunks = Layout::default()
                         .direction(Direction::Vertical)
                         .margin(1)
-                        .constraints([Constraint::Percentage(40), Constraint::Percentage(40), Constraint::Percentage(20)].as_ref())
+                        .constraints([Constraint::Percentage(35), Constraint::Percentage(35), Constraint::Percentage(15), Constraint::Percentage(15)].as_ref())
                         .split(chunks[1]);
                     let block = Block::default()
                         .title_style(Style::default().modifier(Modifier::BOLD))
@@ -79,33 +133,155 @@ impl Ui {
                         None => { app.last_selected }
                     };
 
+                    // Every card may have been deleted, which leaves the panes empty
+                    let empty = Card::new(String::new(), String::new(), Vec::new());
+                    let card = app.cards.get(card_index).unwrap_or(&empty);
+                    // The field being edited is shown in the editor, wrapped the same way as its preview
+                    let editing = |field: Attribute, area: Rect| if app.is_editing && app.field_editing == field {
+                        Some(app.editor.render(inner_width(area), area.height.saturating_sub(2) as usize))
+                    } else {
+                        None
+                    };
+
                     // Card front preview
-                    Paragraph::new([Text::raw(app.cards[card_index].front.clone())].iter())
-                        .block(block.clone().title("Front"))
+                    let front = editing(Attribute::Front, chunks[0])
+                        .unwrap_or_else(|| highlighted(&wrapped(&field_text(&card.front), inner_width(chunks[0])), &app.query.words, None));
+                    Paragraph::new(front.iter())
+                        .block(block.title("Front"))
                         .style(Style::default().fg(Color::Black).bg(Color::White))
                         .alignment(Alignment::Center)
-                        .wrap(true)
                         .render(&mut f, chunks[0]);
 
-                    // Card back preview
-                    Paragraph::new([Text::raw(app.cards[card_index].back.clone().replace("<br>", "\n"))].iter())
-                        .block(block.clone().title("Back"))
+                    // Card back preview. Code blocks are shown left aligned, and so is the back while
+                    // it is edited
+                    let (preview, has_code) = back_preview(&card.back, inner_width(chunks[1]), &app.query.words);
+                    let back = editing(Attribute::Back, chunks[1]).unwrap_or(preview);
+                    Paragraph::new(back.iter())
+                        .block(block.title("Back"))
                         .style(Style::default().fg(Color::Black).bg(Color::White))
-                        .alignment(Alignment::Center)
-                        .wrap(true)
+                        .alignment(if has_code { Alignment::Left } else { Alignment::Center })
                         .render(&mut f, chunks[1]);
 
                     // Card tags preview
-                    Paragraph::new([Text::raw(app.cards[card_index].tags.clone())].iter())
-                        .block(block.clone().title("Tags"))
+                    let tags = editing(Attribute::MainTag, chunks[2])
+                        .unwrap_or_else(|| {
+                            let words = [&app.query.words[..], &app.query.tags[..]].concat();
+                            highlighted(&wrapped(&card.tag_text(), inner_width(chunks[2])), &words, None)
+                        });
+                    Paragraph::new(tags.iter())
+                        .block(block.title("Tags"))
                         .style(Style::default().fg(Color::Black).bg(Color::White))
                         .alignment(Alignment::Center)
-                        .wrap(true)
                         .render(&mut f, chunks[2]);
+
+                    // Card deck preview
+                    let deck = match app.cards.get(card_index) {
+                        Some(card) => card.deck.clone().unwrap_or_else(|| "(export deck)".to_string()),
+                        None => String::new(),
+                    };
+                    Paragraph::new([Text::raw(deck)].iter())
+                        .block(block.title("Deck"))
+                        .style(Style::default().fg(Color::Black).bg(Color::White))
+                        .alignment(Alignment::Center)
+                        .wrap(true)
+                        .render(&mut f, chunks[3]);
                 }
             }
         })?;
 
         Ok(())
     }
+
+    // Render the study screen, with the front of the current card and its back once revealed
+    pub fn render_study(&mut self, study: &Study) -> Result<(), io::Error> {
+        let help = if study.revealed {
+            "[1] again | [2] hard | [3/space] good | [4] easy | [q] quit"
+        } else {
+            "[space] show answer | [q] quit"
+        };
+        let title = format!("Studying: {} left", study.remaining());
+        let front = field_text(&study.front());
+        let back = study.back();
+
+        self.terminal.draw(|mut f| {
+            let size = f.size();
+            Block::default().borders(Borders::ALL).render(&mut f, size);
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .margin(2)
+                .constraints([Constraint::Percentage(20), Constraint::Percentage(40), Constraint::Percentage(40)].as_ref())
+                .split(f.size());
+            let block = Block::default()
+                .title_style(Style::default().modifier(Modifier::BOLD))
+                .borders(Borders::ALL);
+
+            Paragraph::new([Text::raw(help)].iter())
+                .block(Block::default().title(&title).borders(Borders::ALL))
+                .alignment(Alignment::Center)
+                .wrap(true)
+                .render(&mut f, chunks[0]);
+
+            Paragraph::new([Text::raw(wrapped(&front, inner_width(chunks[1])))].iter())
+                .block(block.title("Front"))
+                .style(Style::default().fg(Color::Black).bg(Color::White))
+                .alignment(Alignment::Center)
+                .render(&mut f, chunks[1]);
+
+            let (back, has_code) = back_preview(&back, inner_width(chunks[2]), &[]);
+            Paragraph::new(back.iter())
+                .block(block.title("Back"))
+                .style(Style::default().fg(Color::Black).bg(Color::White))
+                .alignment(if has_code { Alignment::Left } else { Alignment::Center })
+                .render(&mut f, chunks[2]);
+        })?;
+
+        Ok(())
+    }
+}
+
+// Split the back of a card into text and fenced code blocks, which are styled differently.
+// Text is wrapped to the width of the pane like it is while editing, and code isn't wrapped at
+// all, which would lose its indentation. Also returns whether there were any code blocks
+fn back_preview(back: &str, width: usize, words: &[String]) -> (Vec<Text<'static>>, bool) {
+    let code_style = Style::default().fg(Color::Blue).bg(Color::Gray);
+    let mut has_code = false;
+    let text = field_lines(back).into_iter()
+        .flat_map(|line| match line {
+            Line::Text(text) => highlighted(&(wrapped(&text, width) + "\n"), words, None),
+            Line::Code { code, .. } => {
+                has_code = true;
+                highlighted(&code, words, Some(code_style))
+            },
+        })
+        .collect();
+    (text, has_code)
+}
+
+// Split text into pieces so that the words searched for stand out. The rest of the text is in
+// the style given, or the style of the pane
+fn highlighted(text: &str, words: &[String], style: Option<Style>) -> Vec<Text<'static>> {
+    let match_style = Style::default().fg(Color::Black).bg(Color::Yellow);
+    let piece = |text: &str| match style {
+        Some(style) => Text::styled(text.to_string(), style),
+        None => Text::raw(text.to_string()),
+    };
+    let mut found: Vec<_> = words.iter().flat_map(|word| find_all(text, word)).collect();
+    found.sort_by_key(|range| range.start);
+    let mut pieces = Vec::new();
+    let mut end = 0;
+    for range in found {
+        if range.start < end {
+            continue;
+        }
+        pieces.push(piece(&text[end..range.start]));
+        pieces.push(Text::styled(text[range.clone()].to_string(), match_style));
+        end = range.end;
+    }
+    pieces.push(piece(&text[end..]));
+    pieces
+}
+
+// The width of the text inside a pane with borders
+fn inner_width(area: Rect) -> usize {
+    area.width.saturating_sub(2) as usize
 }